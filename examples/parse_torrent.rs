@@ -1,10 +1,8 @@
 use std::io::Read;
 
 
-use crate::decode;
-use crate::torrent::Torrent;
-use crate::de::{from_bytes};
-use crate::decode::ParseDecode;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::de::from_bytes;
 
 
 
@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::tracker::{AnnounceResponse, TrackerEvent, TrackerManager, TrackerRequest};
+
+/// Re-announce interval used when the tracker does not send one
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Wait before trying again when every tracker failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Give up on the `stopped` announce after this long so shutdown is not held up
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes transferred so far, reported to trackers
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> TransferStats {
+        TransferStats { left: AtomicU64::new(left), ..TransferStats::default() }
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a verified piece: downloaded grows and left shrinks by its size
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        // fetch_update only fails when the closure returns None, which it never does
+        let _ = self.left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| Some(left.saturating_sub(bytes)));
+    }
+}
+
+/// Keeps the trackers of a torrent informed for as long as we take part in the swarm
+pub struct Announcer {
+    trackers: TrackerManager,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    stats: Arc<TransferStats>,
+}
+
+impl Announcer {
+    pub fn new(trackers: TrackerManager, info_hash: [u8; 20], peer_id: [u8; 20], port: u16, stats: Arc<TransferStats>) -> Announcer {
+        Announcer { trackers, info_hash, peer_id, port, stats }
+    }
+
+    /// Send the `started` event, then keep re-announcing in the background.
+    /// Returns the first tracker response along with a handle to report later events.
+    pub async fn start(mut self) -> Result<(AnnounceResponse, AnnouncerHandle)> {
+        let response = self.announce(Some(TrackerEvent::Started)).await?;
+        let (events, rx) = mpsc::unbounded_channel();
+        let first_wait = next_interval(&response);
+        let task = tokio::spawn(self.run(rx, first_wait));
+        Ok((response, AnnouncerHandle { events, task }))
+    }
+
+    async fn run(mut self, mut events: mpsc::UnboundedReceiver<TrackerEvent>, mut wait: Duration) {
+        loop {
+            // Events are sent right away, regular announces wait for the interval
+            let event = tokio::select! {
+                _ = tokio::time::sleep(wait) => None,
+                // Dropping the handle means shutting down
+                event = events.recv() => Some(event.unwrap_or(TrackerEvent::Stopped)),
+            };
+            wait = match self.announce(event).await {
+                Ok(response) => next_interval(&response),
+                Err(err) => {
+                    println!("Error announcing to trackers: {:#}", err);
+                    RETRY_INTERVAL
+                }
+            };
+            if event == Some(TrackerEvent::Stopped) {
+                return;
+            }
+        }
+    }
+
+    async fn announce(&mut self, event: Option<TrackerEvent>) -> Result<AnnounceResponse> {
+        let request = TrackerRequest {
+            peer_id: self.peer_id,
+            port: self.port,
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left: self.stats.left(),
+            event,
+            ..TrackerRequest::default()
+        };
+        let response = self.trackers.announce(&self.info_hash, request).await?;
+        if let Some(warning) = &response.warning {
+            println!("Tracker warning: {}", warning);
+        }
+        Ok(response)
+    }
+}
+
+/// Wait the interval asked by the tracker, but never less than its minimum interval
+fn next_interval(response: &AnnounceResponse) -> Duration {
+    let interval = response.interval.map(Duration::from_secs).unwrap_or(DEFAULT_INTERVAL);
+    let min_interval = response.min_interval.map(Duration::from_secs).unwrap_or_default();
+    interval.max(min_interval)
+}
+
+/// Handle to a running announcer
+pub struct AnnouncerHandle {
+    events: mpsc::UnboundedSender<TrackerEvent>,
+    task: JoinHandle<()>,
+}
+
+impl AnnouncerHandle {
+    /// Tell the trackers the download is complete
+    pub fn completed(&self) {
+        let _ = self.events.send(TrackerEvent::Completed);
+    }
+
+    /// Send the `stopped` event and wait for the announcer to finish
+    pub async fn stop(self) {
+        let _ = self.events.send(TrackerEvent::Stopped);
+        if tokio::time::timeout(STOP_TIMEOUT, self.task).await.is_err() {
+            println!("Trackers did not acknowledge the stopped event");
+        }
+    }
+}
@@ -0,0 +1,67 @@
+/// Pieces owned by a peer: one bit per piece, the high bit of the first byte being piece 0
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+    pub fn from_bytes(bytes: Vec<u8>) -> Bitfield {
+        Bitfield(bytes)
+    }
+
+    /// An empty bitfield large enough to hold `npieces` pieces
+    pub fn with_pieces(npieces: usize) -> Bitfield {
+        Bitfield(vec![0; npieces.div_ceil(8)])
+    }
+
+    /// A bitfield with all `npieces` pieces, spare bits of the last byte left clear
+    pub fn full(npieces: usize) -> Bitfield {
+        let mut bitfield = Bitfield::with_pieces(npieces);
+        for index in 0..npieces as u32 {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        let byte = index as usize / 8;
+        let bit = index % 8;
+        self.0.get(byte).is_some_and(|b| b & (0x80 >> bit) != 0)
+    }
+
+    /// Mark the piece at `index` as owned. The bitfield must be large enough to hold it.
+    pub fn set(&mut self, index: u32) {
+        let byte = index as usize / 8;
+        assert!(byte < self.0.len(), "Piece {} out of the bitfield", index);
+        self.0[byte] |= 0x80 >> (index % 8);
+    }
+
+    /// Whether this is a valid bitfield for `npieces` pieces: exactly as many bytes as needed,
+    /// spare bits of the last byte cleared (BEP 3)
+    pub fn fits(&self, npieces: usize) -> bool {
+        if self.0.len() != npieces.div_ceil(8) {
+            return false;
+        }
+        let spare = self.0.len() * 8 - npieces;
+        self.0.last().is_none_or(|&last| last & ((1u16 << spare) - 1) as u8 == 0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits() {
+        assert!(Bitfield::full(10).fits(10));
+        assert!(Bitfield::with_pieces(0).fits(0));
+        assert!(Bitfield::from_bytes(vec![0xff, 0b1100_0000]).fits(10));
+        // Spare bit set
+        assert!(!Bitfield::from_bytes(vec![0xff, 0b1110_0000]).fits(10));
+        // Too short or too long
+        assert!(!Bitfield::from_bytes(vec![0xff]).fits(10));
+        assert!(!Bitfield::from_bytes(vec![0xff, 0, 0]).fits(10));
+    }
+}
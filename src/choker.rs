@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use crate::random::random_u64;
+
+/// Number of peers unchoked for their rate, on top of the optimistic unchoke
+pub const UPLOAD_SLOTS: usize = 4;
+/// Peers are rerated this often
+const RERATE_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of rerates between two rotations of the optimistic unchoke, so every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// Transfers with one peer, updated by its session and read by the choker
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    interested: AtomicBool,
+    /// Bytes of blocks received from the peer
+    downloaded: AtomicU64,
+    /// Bytes of blocks sent to the peer
+    uploaded: AtomicU64,
+}
+
+impl PeerStats {
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A peer known to the choker
+struct Entry {
+    id: u64,
+    stats: Arc<PeerStats>,
+    /// Whether the peer should be choked, watched by its session
+    choked: watch::Sender<bool>,
+    /// Transfer counter at the last rerate
+    last_total: u64,
+    /// Bytes transferred during the last rerate interval
+    rate: u64,
+}
+
+#[derive(Default)]
+struct State {
+    peers: Vec<Entry>,
+    next_id: u64,
+    optimistic: Option<u64>,
+}
+
+/// Decides which peers get unchoked, shared by every session of a torrent.
+/// While downloading the peers we download the most from are unchoked (tit-for-tat),
+/// once seeding the peers we upload the most to. One more peer is unchoked at random
+/// so new peers get a chance to prove themselves.
+#[derive(Default)]
+pub struct Choker {
+    state: Mutex<State>,
+    seeding: AtomicBool,
+    /// Asks for a rerate before the interval is over, when a peer changes its interest
+    rerate: Notify,
+}
+
+impl Choker {
+    /// Create a choker and rerate in the background until it is dropped
+    pub fn start(seeding: bool) -> Arc<Choker> {
+        let choker = Arc::new(Choker { seeding: AtomicBool::new(seeding), ..Choker::default() });
+        tokio::spawn(run(Arc::downgrade(&choker)));
+        choker
+    }
+
+    /// Rate peers by what we upload to them rather than what they send us
+    pub fn set_seeding(&self) {
+        self.seeding.store(true, Ordering::Relaxed);
+    }
+
+    /// Start tracking a peer, choked until the next rerate says otherwise
+    pub fn register(self: &Arc<Choker>) -> ChokerHandle {
+        let stats = Arc::new(PeerStats::default());
+        let (choked, watched) = watch::channel(true);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.peers.push(Entry { id, stats: stats.clone(), choked, last_total: 0, rate: 0 });
+        ChokerHandle { choker: self.clone(), id, stats, choked: watched }
+    }
+
+    /// Choose the peers to unchoke. Rates are only measured on `timed` rerates, which
+    /// rotate the optimistic unchoke every `OPTIMISTIC_ROUNDS`.
+    fn rerate(&self, timed: bool, rotate: bool) {
+        let seeding = self.seeding.load(Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        if timed {
+            for entry in &mut state.peers {
+                let counter = if seeding { &entry.stats.uploaded } else { &entry.stats.downloaded };
+                let total = counter.load(Ordering::Relaxed);
+                // The counter switches once we start seeding
+                entry.rate = total.saturating_sub(entry.last_total);
+                entry.last_total = total;
+            }
+        }
+
+        // Only interested peers are worth a slot
+        let mut candidates: Vec<(u64, u64)> = state.peers.iter()
+            .filter(|entry| entry.stats.interested.load(Ordering::Relaxed))
+            .map(|entry| (entry.id, entry.rate))
+            .collect();
+        candidates.sort_by_key(|&(_, rate)| std::cmp::Reverse(rate));
+        let mut unchoked: Vec<u64> = candidates.iter().take(UPLOAD_SLOTS).map(|&(id, _)| id).collect();
+
+        let others: Vec<u64> = candidates.iter().skip(UPLOAD_SLOTS).map(|&(id, _)| id).collect();
+        let current = state.optimistic.filter(|id| others.contains(id));
+        if rotate || current.is_none() {
+            // Rotating gives the slot to another peer whenever there is one
+            let mut choices: Vec<u64> = others.iter().copied().filter(|&id| Some(id) != current).collect();
+            if choices.is_empty() {
+                choices = others;
+            }
+            state.optimistic = (!choices.is_empty()).then(|| choices[(random_u64() % choices.len() as u64) as usize]);
+        } else {
+            state.optimistic = current;
+        }
+        unchoked.extend(state.optimistic);
+
+        for entry in &state.peers {
+            let choke = !unchoked.contains(&entry.id);
+            entry.choked.send_if_modified(|choked| std::mem::replace(choked, choke) != choke);
+        }
+    }
+}
+
+async fn run(choker: Weak<Choker>) {
+    let mut interval = tokio::time::interval(RERATE_INTERVAL);
+    let mut round = 0;
+    loop {
+        let Some(choker) = choker.upgrade() else {
+            return;
+        };
+        let timed = tokio::select! {
+            _ = interval.tick() => true,
+            _ = choker.rerate.notified() => false,
+        };
+        let rotate = timed && round % OPTIMISTIC_ROUNDS == 0;
+        if timed {
+            round += 1;
+        }
+        choker.rerate(timed, rotate);
+    }
+}
+
+/// A session's link to the choker, the peer is forgotten when it is dropped
+pub struct ChokerHandle {
+    choker: Arc<Choker>,
+    id: u64,
+    stats: Arc<PeerStats>,
+    choked: watch::Receiver<bool>,
+}
+
+impl ChokerHandle {
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    /// Record whether the peer wants our pieces, a change frees or takes a slot right away
+    pub fn set_interested(&self, interested: bool) {
+        if self.stats.interested.swap(interested, Ordering::Relaxed) != interested {
+            self.choker.rerate.notify_one();
+        }
+    }
+
+    /// Wait for the choker to change its mind about the peer, returning whether it is choked
+    pub async fn changed(&mut self) -> bool {
+        if self.choked.changed().await.is_err() {
+            // The entry only goes away with this handle
+            std::future::pending::<()>().await;
+        }
+        *self.choked.borrow_and_update()
+    }
+}
+
+impl Drop for ChokerHandle {
+    fn drop(&mut self) {
+        self.choker.state.lock().unwrap().peers.retain(|entry| entry.id != self.id);
+        // The peer may have held a slot
+        self.choker.rerate.notify_one();
+    }
+}
@@ -1,16 +1,17 @@
 use serde::{de, forward_to_deserialize_any};
 use serde::de::Visitor;
-use crate::decode::{ParseDecode};
-use crate::error::{Error, Result};
+use crate::decode::ParseDecode;
+use crate::error::BencodeError as Error;
+
+type Result<T> = std::result::Result<T, Error>;
 
 struct BAccess<'a, 'de> {
     de: &'a mut BDeserializer<'de>,
-    len: Option<usize>,
 }
 
 impl<'de: 'a, 'a> BAccess<'a, 'de> {
-    fn new(de: &'a mut BDeserializer<'de>, len: Option<usize>) -> Self {
-        BAccess { de, len }
+    fn new(de: &'a mut BDeserializer<'de>) -> Self {
+        BAccess { de }
     }
 }
 
@@ -21,12 +22,13 @@ impl<'a, 'de> de::MapAccess<'de> for BAccess<'a, 'de> {
         where
             K: de::DeserializeSeed<'de>,
     {
-        match self.de.decode()? {
+        match self.de.next_token()? {
             ParseDecode::End => Ok(None),
-            _r => {
-                //self.de.next = Some(r);
+            r @ ParseDecode::Bytes(_) => {
+                self.de.next = Some(r);
                 Ok(Some(seed.deserialize(&mut *self.de)?))
             }
+            r => Err(Error::Message(format!("dictionary keys must be byte strings, found {:?}", r))),
         }
     }
 
@@ -45,72 +47,80 @@ impl<'a, 'de> de::SeqAccess<'de> for BAccess<'a, 'de> {
         &mut self,
         seed: T,
     ) -> Result<Option<T::Value>> {
-        let res = match self.de.decode()? {
+        match self.de.next_token()? {
             ParseDecode::End => Ok(None),
-            _r => {
-                // self.de.next = Some(r);
+            r => {
+                self.de.next = Some(r);
                 Ok(Some(seed.deserialize(&mut *self.de)?))
             }
-        };
-        if let Some(l) = self.len {
-            let l = l - 1;
-            self.len = Some(l);
-            if l == 0 && ParseDecode::End != self.de.decode()? {
-                return Err(Error::Message("expected `e`".to_string()));
-            }
         }
-        res
     }
 }
 
 pub struct BDeserializer<'de> {
     input: &'de [u8],
+    /// Token read ahead by a map or sequence to detect its end
+    next: Option<ParseDecode>,
 }
 
 impl<'de> BDeserializer<'de> {
     pub fn from_bytes(input: &'de [u8]) -> Self {
-        BDeserializer { input }
+        BDeserializer { input, next: None }
+    }
+
+    fn next_token(&mut self) -> Result<ParseDecode> {
+        match self.next.take() {
+            Some(token) => Ok(token),
+            None => self.decode(),
+        }
     }
 
-    fn decode(&self) -> Result<ParseDecode> {
-        let c = self.input[0];
+    fn decode(&mut self) -> Result<ParseDecode> {
+        let c = *self.input.first().ok_or(Error::Eof)?;
         match c {
             b'i' => Ok(ParseDecode::Integer(self.decode_integer()?)),
-            b'l' => Ok(ParseDecode::List),
-            b'd' => Ok(ParseDecode::Dictionary),
-            b'e' => Ok(ParseDecode::End),
+            b'l' => {
+                self.input = &self.input[1..];
+                Ok(ParseDecode::List)
+            }
+            b'd' => {
+                self.input = &self.input[1..];
+                Ok(ParseDecode::Dictionary)
+            }
+            b'e' => {
+                self.input = &self.input[1..];
+                Ok(ParseDecode::End)
+            }
             b'0'..=b'9' => Ok(ParseDecode::Bytes(self.decode_bytes()?)),
             _ => Err(Error::Message(format!(
                 "Invalid character `{}`",
                 c as char
             )))
         }
-        // Ok(c)
     }
 
-    fn decode_bytes(&self) -> Result<Vec<u8>> {
-        // Review it
-        let mut i = 1;
-        while self.input[i] != b':' {
-            i += 1;
-        }
-        let s = std::str::from_utf8(&self.input[1..i]).unwrap();
-        let length = s.parse::<usize>().unwrap();
-        let bytes = &self.input[i + 1..i + 1 + length];
+    fn decode_bytes(&mut self) -> Result<Vec<u8>> {
+        let i = self.input.iter().position(|&b| b == b':').ok_or(Error::Eof)?;
+        let length = std::str::from_utf8(&self.input[..i]).ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| Error::Message("Invalid byte string length".to_string()))?;
+        let end = (i + 1).checked_add(length).ok_or(Error::Eof)?;
+        let bytes = self.input.get(i + 1..end).ok_or(Error::Eof)?;
+        self.input = &self.input[end..];
         Ok(bytes.to_vec())
     }
 
-    fn decode_integer(&self) -> Result<i64> {
-        let mut i = 1;
-        while self.input[i] != b'e' {
-            i += 1;
-        }
-        let s = std::str::from_utf8(&self.input[1..i]).unwrap();
-        Ok(s.parse::<i64>().unwrap())
+    fn decode_integer(&mut self) -> Result<i64> {
+        let i = self.input.iter().position(|&b| b == b'e').ok_or(Error::Eof)?;
+        let number = std::str::from_utf8(&self.input[1..i]).ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| Error::Message("Invalid integer".to_string()))?;
+        self.input = &self.input[i + 1..];
+        Ok(number)
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut BDeserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut BDeserializer<'de> {
     type Error = Error;
 
     // Look at the input data to decide what Serde data model type to
@@ -120,34 +130,38 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut BDeserializer<'de> {
         where
             V: Visitor<'de>,
     {
-        match self.decode()? {
-            ParseDecode::Dictionary => visitor.visit_map(BAccess::new(self, None)),
-            ParseDecode::List => visitor.visit_seq(BAccess::new(self, None)),
+        match self.next_token()? {
+            ParseDecode::Dictionary => visitor.visit_map(BAccess::new(self)),
+            ParseDecode::List => visitor.visit_seq(BAccess::new(self)),
             ParseDecode::Integer(num) => visitor.visit_i64(num),
             ParseDecode::Bytes(b) => visitor.visit_bytes(b.as_ref()),
-            ParseDecode::End => visitor.visit_unit(),
+            ParseDecode::End => Err(Error::Message("unexpected `e`".to_string())),
         }
     }
 
+    // Bencode has no null: a key that is present always holds a value
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+        where
+            V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
         tuple_struct map struct enum identifier ignored_any
     }
-
-    // fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
-    //     where
-    //         V: Visitor<'de>,
-    // {
-    //     visitor.visit_u64();
-    // }
 }
 
 pub fn from_bytes<'de, T>(b: &'de [u8]) -> Result<T>
     where
         T: de::Deserialize<'de>,
 {
-    de::Deserialize::deserialize(&mut BDeserializer::from_bytes(b))
+    let mut deserializer = BDeserializer::from_bytes(b);
+    let value = de::Deserialize::deserialize(&mut deserializer)?;
+    if !deserializer.input.is_empty() || deserializer.next.is_some() {
+        return Err(Error::Message("trailing characters after value".to_string()));
+    }
+    Ok(value)
 }
-
@@ -1,38 +1,80 @@
 use crate::value::BencodeValue;
 use crate::error::BencodeError as Error;
 use anyhow::Result;
-use std::io::Read;
+use std::ops::Range;
 use linked_hash_map::LinkedHashMap;
 
-// #[derive(Debug, PartialEq)]
-// pub enum ParseDecode {
-//     Integer(i64),
-//     Bytes(Vec<u8>),
-//     List,
-//     Dictionary,
-//     End,
-// }
+/// A single bencode token, as read by the serde deserializer
+#[derive(Debug, PartialEq)]
+pub enum ParseDecode {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List,
+    Dictionary,
+    End,
+}
+
+/// Where a parsed value sits in the original input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    /// Nesting level of the value, 0 being the top-level value
+    pub depth: usize,
+    /// Dictionary key the value is stored under, if any
+    pub key: Option<Vec<u8>>,
+    /// Byte range of the encoded value in the input
+    pub range: Range<usize>,
+}
 
 pub struct Parser<'a> {
     input: &'a [u8],
+    length: usize,
+    depth: usize,
+    spans: Vec<Span>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a [u8]) -> Self {
         Parser {
             input,
+            length: input.len(),
+            depth: 0,
+            spans: Vec::new(),
         }
     }
 
+    /// Number of bytes consumed so far
+    pub fn position(&self) -> usize {
+        self.length - self.input.len()
+    }
+
+    /// Spans of every value parsed so far, each one recorded once the value is complete
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// Byte range of the value stored under `key` in a dictionary at `depth`
+    pub fn span_of(&self, depth: usize, key: &[u8]) -> Option<Range<usize>> {
+        self.spans
+            .iter()
+            .find(|span| span.depth == depth && span.key.as_deref() == Some(key))
+            .map(|span| span.range.clone())
+    }
+
+    /// Read the next byte, failing at the end of the input
+    fn next_byte(&mut self) -> Result<u8> {
+        let (&byte, rest) = self.input.split_first().ok_or(Error::Eof)?;
+        self.input = rest;
+        Ok(byte)
+    }
+
     pub fn parse_number(&mut self) -> Result<i64> {
         let mut num_bytes: Vec<u8> = Vec::new();
         loop {
-            let mut buf: [u8; 1] = [0; 1];
-            self.input.read(&mut buf).map_err(|_| Error::Message("Error reading number".to_string()))?;
-            if buf[0] == b'e' {
+            let byte = self.next_byte()?;
+            if byte == b'e' {
                 break;
             }
-            num_bytes.push(buf[0]);
+            num_bytes.push(byte);
         }
         let num_string = String::from_utf8(num_bytes).map_err(|_| Error::Message("Error converting number to string from_utf8".to_string()))?;
         let number = num_string.parse::<i64>().map_err(|_| Error::Message("Error parsing number".to_string()))?;
@@ -40,25 +82,24 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse_bytes(&mut self, len: i64) -> Result<Vec<u8>> {
-        let mut bytes: Vec<u8> = Vec::new();
-        for _ in 0..len {
-            let mut buf: [u8; 1] = [0; 1];
-            self.input.read(&mut buf).map_err(|_| Error::Message("Error reading number".to_string()))?;
-            bytes.push(buf[0]);
+        // The length comes from the input, it cannot be trusted to fit in it
+        if len < 0 || len as u64 > self.input.len() as u64 {
+            return Err(Error::Message(format!("Invalid string length {}, {} bytes left", len, self.input.len())).into());
         }
-        Ok(bytes)
+        let (bytes, rest) = self.input.split_at(len as usize);
+        self.input = rest;
+        Ok(bytes.to_vec())
     }
 
     pub fn parse_string_len(&mut self, first: u8) -> Result<i64> {
         let mut num_bytes: Vec<u8> = Vec::new();
         num_bytes.push(first);
         loop {
-            let mut buf: [u8; 1] = [0; 1];
-            self.input.read(&mut buf).map_err(|_| Error::Message("Error reading number".to_string()))?;
-            if buf[0] == b':' {
+            let byte = self.next_byte()?;
+            if byte == b':' {
                 break;
             }
-            num_bytes.push(buf[0]);
+            num_bytes.push(byte);
         }
         let num_string = String::from_utf8(num_bytes).map_err(|_| Error::Message("Error converting number to string from_utf8".to_string()))?;
         let number = num_string.parse::<i64>().map_err(|_| Error::Message("Error parsing number".to_string()))?;
@@ -68,7 +109,7 @@ impl<'a> Parser<'a> {
     pub fn parse_list(&mut self) -> Result<BencodeValue> {
         let mut list = Vec::new();
         loop {
-            let decoded_value = self.parse()?;
+            let decoded_value = self.parse_nested()?;
             match decoded_value {
                 BencodeValue::BEnd => {
                     break;
@@ -84,10 +125,13 @@ impl<'a> Parser<'a> {
     pub fn parse_dictionary(&mut self) -> Result<BencodeValue> {
         let mut map = LinkedHashMap::new();
         loop {
-            let key = self.parse().map_err(|_| Error::Message("Error mapping key input".to_string()))?;
+            let key = self.parse_nested().map_err(|_| Error::Message("Error mapping key input".to_string()))?;
             match key {
                 BencodeValue::BString(key_string) => {
-                    let value = self.parse()?;
+                    let value = self.parse_nested()?;
+                    if let Some(span) = self.spans.last_mut() {
+                        span.key = Some(key_string.clone());
+                    }
                     map.insert(key_string, value);
                 }
                 BencodeValue::BEnd => {
@@ -100,12 +144,28 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Parse a value inside a list or dictionary
+    fn parse_nested(&mut self) -> Result<BencodeValue> {
+        self.depth += 1;
+        let value = self.parse();
+        self.depth -= 1;
+        value
+    }
+
     pub fn parse(&mut self) -> Result<BencodeValue> {
+        let start = self.position();
+        let value = self.parse_value()?;
+        if value != BencodeValue::BEnd {
+            self.spans.push(Span { depth: self.depth, key: None, range: start..self.position() });
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<BencodeValue> {
 
         // Read byte character
-        let mut buf: [u8; 1] = [0; 1];
-        self.input.read(&mut buf).map_err(|_| Error::Message("Error reading input".to_string()))?;
-        return match buf[0] {
+        let byte = self.next_byte()?;
+        match byte {
             b'i' => {
                 // Example: "i52e" -> "52"
                 let number = self.parse_number()?;
@@ -133,9 +193,9 @@ impl<'a> Parser<'a> {
                 Ok(BencodeValue::BEnd)
             }
             _ => {
-                Err(Error::Message(format!("Invalid character `{}`", buf[0])).into())
+                Err(Error::Message(format!("Invalid character `{}`", byte)).into())
             }
-        };
+        }
     }
 }
 
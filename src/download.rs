@@ -0,0 +1,131 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use crate::BLOCK_SIZE;
+use crate::listener::InboundPeer;
+use crate::peers::PeerMessage;
+use crate::scheduler::Scheduler;
+use crate::session::{PeerSession, SNUB_TIMEOUT};
+use crate::torrent::Torrent;
+use crate::upload::PieceStore;
+
+/// Default number of block requests kept in flight per peer
+pub const DEFAULT_PIPELINE: usize = 5;
+
+/// Download every block of a piece, keeping up to `pipeline` block requests in flight.
+/// When the peer chokes us mid-piece, the blocks it dropped are requested again once unchoked.
+pub async fn download_piece(session: &mut PeerSession, torrent: &Torrent, piece_index: u32, pipeline: usize) -> Result<Vec<u8>> {
+    if piece_index >= torrent.piece_count() {
+        bail!("Piece {} out of range, the torrent has {} pieces", piece_index, torrent.piece_count());
+    }
+    let piece_size = torrent.piece_size(piece_index);
+    let mut all_blocks: Vec<u8> = vec![0; piece_size as usize];
+
+    let nblocks = torrent.block_count(piece_index);
+    let mut received = vec![false; nblocks as usize];
+    let mut missing = nblocks;
+    session.last_block = Instant::now();
+
+    while missing > 0 {
+        if session.can_request(piece_index) {
+            // Fill the window with blocks neither received nor requested yet
+            for block in 0..nblocks {
+                if session.requests.len() >= pipeline.max(1) {
+                    break;
+                }
+                let begin = block * BLOCK_SIZE;
+                if received[block as usize] || session.requests.contains_key(&(piece_index, begin)) {
+                    continue;
+                }
+                let length = torrent.block_size(piece_index, block);
+                session.send(PeerMessage::Request { index: piece_index, begin, length }).await
+                    .with_context(|| format!("Error sending request for block {}", block))?;
+            }
+        }
+
+        // Whether it chokes us or not, a peer sending no block is of no use
+        let message = tokio::time::timeout_at(session.last_block + SNUB_TIMEOUT, session.next_message()).await
+            .context("Peer stopped sending blocks")?
+            .context("Piece message was invalid")?;
+        match message {
+            PeerMessage::Piece { index, begin, block } if index == piece_index => {
+                // Blocks may arrive in any order, even after a choke dropped their request
+                let block_index = begin / BLOCK_SIZE;
+                if begin % BLOCK_SIZE != 0 || block_index >= nblocks || received[block_index as usize] {
+                    continue;
+                }
+                if block.len() != torrent.block_size(piece_index, block_index) as usize {
+                    bail!("Unexpected block: index {}, offset {}, length {}", index, begin, block.len());
+                }
+                let offset = begin as usize;
+                all_blocks[offset..offset + block.len()].copy_from_slice(&block);
+                received[block_index as usize] = true;
+                missing -= 1;
+            }
+            // Rejects are expected for the requests a choke dropped, and stop requests of the piece
+            // until the next unchoke. Otherwise the peer refuses to serve us.
+            PeerMessage::RejectRequest { index, begin, .. } if index == piece_index && !session.peer_choking => {
+                bail!("Peer rejected the request for offset {}", begin);
+            }
+            _ => {}
+        }
+    }
+
+    // Blocks requested again after a choke may have arrived from the first request
+    let leftover: Vec<(u32, u32, u32)> = session.requests.iter()
+        .filter(|&(&(index, _), _)| index == piece_index)
+        .map(|(&(index, begin), &length)| (index, begin, length))
+        .collect();
+    for (index, begin, length) in leftover {
+        session.send(PeerMessage::Cancel { index, begin, length }).await.context("Error cancelling request")?;
+    }
+
+    Ok(all_blocks)
+}
+
+/// Check a downloaded piece against its hash in the torrent
+pub fn verify_piece(torrent: &Torrent, piece_index: u32, data: &[u8]) -> bool {
+    match torrent.piece_hash(piece_index) {
+        Some(expected) => Sha1::digest(data).as_slice() == expected,
+        None => false,
+    }
+}
+
+/// Download the given pieces from as many peers as possible
+pub async fn download_pieces(peers: Vec<SocketAddr>, torrent: Arc<Torrent>, info_hash: &[u8; 20], peer_id: &[u8; 20], pieces: Vec<u32>, pipeline: usize) -> Result<Vec<(u32, Vec<u8>)>> {
+    if let Some(index) = pieces.iter().find(|&&index| index >= torrent.piece_count()) {
+        bail!("Piece {} out of range, the torrent has {} pieces", index, torrent.piece_count());
+    }
+    let wanted = pieces.len();
+    let mut received = Scheduler::new(torrent, *info_hash, *peer_id, pieces, pipeline, None).start(peers, None);
+
+    let mut downloaded = Vec::with_capacity(wanted);
+    while let Some(piece) = received.recv().await {
+        downloaded.push(piece);
+        if downloaded.len() == wanted {
+            return Ok(downloaded);
+        }
+    }
+    bail!("No peers left to download from, {} of {} pieces downloaded", downloaded.len(), wanted)
+}
+
+/// Download the pieces scheduled and keep them in the store, which serves them to other peers.
+/// Peers connecting to us are downloaded from as well when `inbound` is given.
+pub async fn download_all(peers: Vec<SocketAddr>, inbound: Option<mpsc::Receiver<InboundPeer>>, scheduler: Scheduler, store: &PieceStore) -> Result<()> {
+    let wanted = scheduler.pending();
+    let mut received = scheduler.start(peers, inbound);
+
+    // Pieces are written as soon as they arrive so they are not kept in memory
+    let mut downloaded = 0;
+    while let Some((index, data)) = received.recv().await {
+        store.store_piece(index, &data)?;
+        downloaded += 1;
+        if downloaded == wanted {
+            return Ok(());
+        }
+    }
+    bail!("No peers left to download from, {} of {} pieces downloaded", downloaded, wanted)
+}
@@ -4,15 +4,14 @@ use crate::value::BencodeValue;
 use anyhow::Result;
 
 
+#[derive(Default)]
 pub struct Encoder {
     buf: Vec<u8>,
 }
 
 impl Encoder {
     pub fn new() -> Self {
-        Encoder {
-            buf: Vec::new(),
-        }
+        Encoder::default()
     }
 
     /// Encode info bytes to a sha1 hash (both hex and byte string representation)
@@ -32,6 +31,11 @@ impl Encoder {
         hex_string
     }
 
+    /// The bytes encoded so far
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
     pub fn encode(&mut self, input: &BencodeValue) -> Result<()> {
         match input {
             BencodeValue::BString(msg) => {
@@ -73,7 +77,7 @@ impl Encoder {
         Ok(())
     }
 
-    pub fn encode_string(&mut self, bytes: &Vec<u8>) -> Result<()> {
+    pub fn encode_string(&mut self, bytes: &[u8]) -> Result<()> {
         let length = bytes.len();
         // Add each byte to the buffer
         length.to_string().as_bytes().iter().for_each(|b| self.buf.push(*b));
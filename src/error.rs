@@ -37,4 +37,35 @@ impl Display for BencodeError {
     }
 }
 
+/// Errors reported by a tracker or caused by its answer
+#[derive(Error, Debug)]
+pub enum TrackerError {
+    /// The tracker refused the request, with the reason it gave
+    #[error("Tracker failure: {0}")]
+    Failure(String),
+    #[error("Tracker answered with HTTP status {0}")]
+    Status(reqwest::StatusCode),
+    #[error("Invalid tracker response: {0}")]
+    InvalidResponse(BencodeError),
+    #[error("Tracker response has no peers")]
+    MissingPeers,
+    /// The announce URL does not end with `announce`, so there is no scrape URL (BEP 48)
+    #[error("Tracker {0} does not support scrape")]
+    NoScrape(String),
+}
 
+/// Ways a peer handshake can fail
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("Peer did not complete the handshake in time")]
+    Timeout,
+    #[error("Peer closed the connection during the handshake")]
+    Closed,
+    /// The peer does not speak the BitTorrent protocol
+    #[error("Not a BitTorrent handshake")]
+    InvalidProtocol,
+    #[error("Peer answered for info hash {}, expected {}", hex::encode(.received), hex::encode(.expected))]
+    InfoHashMismatch { expected: [u8; 20], received: [u8; 20] },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
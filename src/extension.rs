@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use bytes::Bytes;
+use crate::peers::PeerMessage;
+
+/// Extended message id of the extension handshake
+pub const HANDSHAKE_ID: u8 = 0;
+/// Client name and version sent in the `v` key
+pub const CLIENT_VERSION: &str = concat!("bittorrent-starter-rust ", env!("CARGO_PKG_VERSION"));
+/// Number of outstanding requests we accept from a peer, sent in the `reqq` key
+pub const DEFAULT_REQQ: i64 = 250;
+
+/// Bencoded dictionary exchanged right after the handshake by peers supporting BEP 10
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the message id the sender wants to receive them with.
+    /// An id of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Client name and version, not always valid UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+    /// Listen port of the sender
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_bytes(payload: &[u8]) -> Result<ExtensionHandshake> {
+        crate::de::from_bytes(payload).context("Invalid extension handshake")
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).context("Error encoding extension handshake")
+    }
+
+    /// Client name and version of the sender, for display
+    pub fn client(&self) -> Option<String> {
+        self.v.as_ref().map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+
+    /// Id the sender wants to receive the extension with, `None` if it does not support it
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|&id| id > 0 && id <= u8::MAX as i64).map(|id| id as u8)
+    }
+}
+
+/// Extensions we support, each one with the message id peers must use to send it to us
+#[derive(Debug, Default, Clone)]
+pub struct ExtensionRegistry {
+    /// The extension with id `n` is stored at index `n - 1`
+    names: Vec<&'static str>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> ExtensionRegistry {
+        ExtensionRegistry::default()
+    }
+
+    /// Register an extension and return its local message id
+    pub fn register(&mut self, name: &'static str) -> u8 {
+        if let Some(id) = self.id_of(name) {
+            return id;
+        }
+        self.names.push(name);
+        self.names.len() as u8
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.names.iter().position(|&n| n == name).map(|index| index as u8 + 1)
+    }
+
+    /// Name of the extension a peer message with this id is meant for
+    pub fn name_of(&self, id: u8) -> Option<&'static str> {
+        self.names.get((id as usize).checked_sub(1)?).copied()
+    }
+
+    /// Our extension handshake, advertising every registered extension
+    pub fn handshake(&self) -> ExtensionHandshake {
+        ExtensionHandshake {
+            m: self.names.iter().enumerate().map(|(index, name)| (name.to_string(), index as i64 + 1)).collect(),
+            v: Some(ByteBuf::from(CLIENT_VERSION)),
+            reqq: Some(DEFAULT_REQQ),
+            ..ExtensionHandshake::default()
+        }
+    }
+}
+
+/// Build an extended message: the extended message id followed by the extension payload
+pub fn extended_message(id: u8, body: &[u8]) -> PeerMessage {
+    PeerMessage::Extended { id, payload: Bytes::copy_from_slice(body) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut registry = ExtensionRegistry::new();
+        registry.register("ut_metadata");
+        let handshake = registry.handshake();
+        assert_eq!(ExtensionHandshake::from_bytes(&handshake.to_bytes().unwrap()).unwrap(), handshake);
+        assert_eq!(handshake.id_of("ut_metadata"), Some(1));
+    }
+
+    #[test]
+    fn non_utf8_client() {
+        let handshake = ExtensionHandshake::from_bytes(b"d1:md11:ut_metadatai3ee13:metadata_sizei100e1:v4:\xb5T\xff1e").unwrap();
+        assert_eq!(handshake.id_of("ut_metadata"), Some(3));
+        assert_eq!(handshake.metadata_size, Some(100));
+        assert_eq!(handshake.client().as_deref(), Some("\u{fffd}T\u{fffd}1"));
+    }
+}
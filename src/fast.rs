@@ -0,0 +1,49 @@
+use std::net::Ipv4Addr;
+use sha1::{Digest, Sha1};
+
+/// Number of pieces a peer may download from us while choked
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Pieces the peer at `ip` may request while choked, generated as described in BEP 6
+/// so that the peer gets the same set whichever of its connections asks
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], piece_count: u32, count: usize) -> Vec<u32> {
+    let count = count.min(piece_count as usize);
+    let mut allowed = Vec::with_capacity(count);
+    // Only the /24 network counts, peers behind the same NAT share their set
+    let mut x = (u32::from(ip) & 0xffffff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() == count {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().expect("chunk is 4 bytes long")) % piece_count;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bep6_vectors() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+    }
+
+    #[test]
+    fn small_torrents() {
+        // A torrent with fewer pieces than the set size allows every piece
+        let mut allowed = allowed_fast_set(Ipv4Addr::new(10, 0, 0, 1), &[0x11; 20], 3, ALLOWED_FAST_COUNT);
+        allowed.sort();
+        assert_eq!(allowed, vec![0, 1, 2]);
+    }
+}
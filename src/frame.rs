@@ -1,37 +1,72 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::io;
-use tokio_util::codec::{Decoder, Encoder};
-use crate::peers::{PeerMessage, PeerMessageType};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+use crate::bitfield::Bitfield;
+use crate::peers::PeerMessage;
 
 const MAX: u32 = 8 * 1024 * 1024;
 
 pub struct MessageDecoder;
 
+impl MessageDecoder {
+    /// Frame messages of a peer whose handshake is done, starting with the bytes it sent behind it
+    pub fn framed(stream: TcpStream, buffered: BytesMut) -> Framed<TcpStream, MessageDecoder> {
+        let mut parts = FramedParts::new::<PeerMessage>(stream, MessageDecoder);
+        parts.read_buf = buffered;
+        Framed::from_parts(parts)
+    }
+}
 
 impl Encoder<PeerMessage> for MessageDecoder {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // A keep-alive is a bare zero length
+        let Some(id) = message_id(&item) else {
+            dst.put_u32(0);
+            return Ok(());
+        };
+        let payload_length = payload_length(&item);
+
         // Don't send a string if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1> MAX as usize {
+        if payload_length + 1> MAX as usize {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len())
+                format!("Frame of length {} is too large.", payload_length)
             ));
         }
 
-        // Convert the length into a byte array.
-        // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
-
         // Reserve space in the buffer.
-        dst.reserve(4 + item.length as usize);
+        dst.reserve(5 + payload_length);
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.id as u8);
-        dst.extend_from_slice(&item.payload);
+        // Write the length, the id and the payload to the buffer.
+        // The cast to u32 cannot overflow due to the length check above.
+        dst.put_u32(payload_length as u32 + 1);
+        dst.put_u8(id);
+        match item {
+            PeerMessage::Have(index) | PeerMessage::SuggestPiece(index) | PeerMessage::AllowedFast(index) => dst.put_u32(index),
+            PeerMessage::Bitfield(bitfield) => dst.extend_from_slice(bitfield.as_bytes()),
+            PeerMessage::Request { index, begin, length }
+            | PeerMessage::Cancel { index, begin, length }
+            | PeerMessage::RejectRequest { index, begin, length } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            PeerMessage::Piece { index, begin, block } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.extend_from_slice(&block);
+            }
+            PeerMessage::Extended { id, payload } => {
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+            PeerMessage::Unknown(_, payload) => dst.extend_from_slice(&payload),
+            _ => {}
+        }
         Ok(())
     }
 }
@@ -50,6 +85,15 @@ impl Decoder for MessageDecoder {
         length_bytes.copy_from_slice(&src[..4]);
         let length = u32::from_be_bytes(length_bytes) as usize;
 
+        // Check the length before buffering anything, so a peer cannot make us
+        // allocate whatever it announces.
+        if length > MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Frame of length {} is too large.", length)
+            ));
+        }
+
         if src.len() < 4 + length {
             // The full string has not yet arrived.
             //
@@ -62,27 +106,171 @@ impl Decoder for MessageDecoder {
             return Ok(None);
         }
 
-        let id = src[4];
-        let message_type = match id {
-            1 => PeerMessageType::Unchoke,
-            2 => PeerMessageType::Interested,
-            3 => PeerMessageType::NotInterested,
-            4 =>PeerMessageType::Have,
-            5 => PeerMessageType::Bitfield,
-            6 => PeerMessageType::Request,
-            7 => PeerMessageType::Piece,
-            8 => PeerMessageType::Cancel,
-            9 => PeerMessageType::KeepAlive,
-            _ => PeerMessageType::KeepAlive,
-        };
-        let payload = src[5..length + 4].to_vec();
+        // A zero length frame is a keep-alive, it has no id
+        if length == 0 {
+            src.advance(4);
+            return Ok(Some(PeerMessage::KeepAlive));
+        }
+
+        // The payload shares the read buffer, blocks are never copied
+        let mut frame = src.split_to(4 + length);
+        frame.advance(4);
+        let id = frame.get_u8();
+        parse_message(id, frame.freeze()).map(Some)
+    }
+}
+
+fn message_id(message: &PeerMessage) -> Option<u8> {
+    let id = match message {
+        PeerMessage::KeepAlive => return None,
+        PeerMessage::Choke => 0,
+        PeerMessage::Unchoke => 1,
+        PeerMessage::Interested => 2,
+        PeerMessage::NotInterested => 3,
+        PeerMessage::Have(_) => 4,
+        PeerMessage::Bitfield(_) => 5,
+        PeerMessage::Request { .. } => 6,
+        PeerMessage::Piece { .. } => 7,
+        PeerMessage::Cancel { .. } => 8,
+        PeerMessage::SuggestPiece(_) => 13,
+        PeerMessage::HaveAll => 14,
+        PeerMessage::HaveNone => 15,
+        PeerMessage::RejectRequest { .. } => 16,
+        PeerMessage::AllowedFast(_) => 17,
+        PeerMessage::Extended { .. } => 20,
+        PeerMessage::Unknown(id, _) => *id,
+    };
+    Some(id)
+}
+
+/// Length of the payload following the id
+fn payload_length(message: &PeerMessage) -> usize {
+    match message {
+        PeerMessage::Have(_) | PeerMessage::SuggestPiece(_) | PeerMessage::AllowedFast(_) => 4,
+        PeerMessage::Bitfield(bitfield) => bitfield.as_bytes().len(),
+        PeerMessage::Request { .. } | PeerMessage::Cancel { .. } | PeerMessage::RejectRequest { .. } => 12,
+        PeerMessage::Piece { block, .. } => 8 + block.len(),
+        PeerMessage::Extended { payload, .. } => 1 + payload.len(),
+        PeerMessage::Unknown(_, payload) => payload.len(),
+        _ => 0,
+    }
+}
+
+fn parse_message(id: u8, mut payload: Bytes) -> Result<PeerMessage, io::Error> {
+    // Payload length of the messages whose size is fixed
+    let expected = match id {
+        0..=3 | 14 | 15 => Some(0),
+        4 | 13 | 17 => Some(4),
+        6 | 8 | 16 => Some(12),
+        _ => None,
+    };
+    let too_short = match id {
+        7 => payload.len() < 8,
+        20 => payload.is_empty(),
+        _ => false,
+    };
+    if expected.is_some_and(|expected| payload.len() != expected) || too_short {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Message {} has an invalid length of {}.", id, payload.len() + 1)
+        ));
+    }
+
+    let message = match id {
+        0 => PeerMessage::Choke,
+        1 => PeerMessage::Unchoke,
+        2 => PeerMessage::Interested,
+        3 => PeerMessage::NotInterested,
+        4 => PeerMessage::Have(payload.get_u32()),
+        5 => PeerMessage::Bitfield(Bitfield::from_bytes(payload.to_vec())),
+        6 => PeerMessage::Request { index: payload.get_u32(), begin: payload.get_u32(), length: payload.get_u32() },
+        7 => PeerMessage::Piece { index: payload.get_u32(), begin: payload.get_u32(), block: payload },
+        8 => PeerMessage::Cancel { index: payload.get_u32(), begin: payload.get_u32(), length: payload.get_u32() },
+        13 => PeerMessage::SuggestPiece(payload.get_u32()),
+        14 => PeerMessage::HaveAll,
+        15 => PeerMessage::HaveNone,
+        16 => PeerMessage::RejectRequest { index: payload.get_u32(), begin: payload.get_u32(), length: payload.get_u32() },
+        17 => PeerMessage::AllowedFast(payload.get_u32()),
+        20 => PeerMessage::Extended { id: payload.get_u8(), payload },
+        id => PeerMessage::Unknown(id, payload),
+    };
+    Ok(message)
+}
 
-        src.advance(length + 4);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(message: PeerMessage) -> BytesMut {
+        let mut buf = BytesMut::new();
+        MessageDecoder.encode(message, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have(7),
+            PeerMessage::Bitfield(Bitfield::from_bytes(vec![0b1010_0000, 0xff])),
+            PeerMessage::Request { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Piece { index: 1, begin: 16384, block: Bytes::from_static(b"block") },
+            PeerMessage::Cancel { index: 1, begin: 0, length: 16384 },
+            PeerMessage::SuggestPiece(3),
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest { index: 2, begin: 0, length: 16384 },
+            PeerMessage::AllowedFast(9),
+            PeerMessage::Extended { id: 0, payload: Bytes::from_static(b"d1:md11:ut_metadatai1eee") },
+            PeerMessage::Unknown(42, Bytes::from_static(b"unknown")),
+        ];
+        for message in messages {
+            let mut buf = encode(message.clone());
+            assert_eq!(MessageDecoder.decode(&mut buf).unwrap(), Some(message));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn keep_alive_followed_by_message() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 0, 0, 0, 5, 4, 0, 0, 0, 7][..]);
+        assert_eq!(MessageDecoder.decode(&mut buf).unwrap(), Some(PeerMessage::KeepAlive));
+        assert_eq!(MessageDecoder.decode(&mut buf).unwrap(), Some(PeerMessage::Have(7)));
+        assert_eq!(MessageDecoder.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn partial_frame() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 5, 4, 0, 0][..]);
+        assert_eq!(MessageDecoder.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&[0, 7]);
+        assert_eq!(MessageDecoder.decode(&mut buf).unwrap(), Some(PeerMessage::Have(7)));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX + 1);
+        assert!(MessageDecoder.decode(&mut buf).is_err());
+    }
 
-        Ok(Some(PeerMessage {
-            length: length as u32,
-            id: message_type,
-            payload,
-        }))
+    #[test]
+    fn wrong_fixed_length() {
+        // Have with 3 bytes of payload, then with 12
+        let mut short = BytesMut::from(&[0, 0, 0, 4, 4, 0, 0, 7][..]);
+        assert!(MessageDecoder.decode(&mut short).is_err());
+        let mut long = BytesMut::from(&[0, 0, 0, 13, 4, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0][..]);
+        assert!(MessageDecoder.decode(&mut long).is_err());
+        let mut request = BytesMut::from(&[0, 0, 0, 12, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 64][..]);
+        assert!(MessageDecoder.decode(&mut request).is_err());
+        // Unchoke with a payload and a piece without its offset
+        let mut unchoke = BytesMut::from(&[0, 0, 0, 2, 1, 0][..]);
+        assert!(MessageDecoder.decode(&mut unchoke).is_err());
+        let mut piece = BytesMut::from(&[0, 0, 0, 5, 7, 0, 0, 0, 1][..]);
+        assert!(MessageDecoder.decode(&mut piece).is_err());
     }
 }
\ No newline at end of file
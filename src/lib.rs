@@ -0,0 +1,27 @@
+pub mod decode;
+pub mod de;
+pub mod value;
+pub mod torrent;
+pub mod error;
+pub mod encode;
+pub mod peers;
+pub mod tracker;
+pub mod frame;
+pub mod download;
+pub mod bitfield;
+pub mod scheduler;
+pub mod storage;
+pub mod magnet;
+pub mod metadata;
+pub mod extension;
+pub mod udp_tracker;
+pub mod random;
+pub mod announcer;
+pub mod listener;
+pub mod upload;
+pub mod choker;
+pub mod fast;
+pub mod session;
+
+/// Size of the blocks requested from peers (16 KiB)
+pub const BLOCK_SIZE: u32 = 16 * 1024;
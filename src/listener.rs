@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use crate::peers::{read_handshake, send_handshake, Handshake, HANDSHAKE_TIMEOUT};
+
+/// Default port to listen on for inbound peers
+pub const DEFAULT_PORT: u16 = 6881;
+/// Inbound connections waiting to be taken over by their torrent
+const INBOUND_QUEUE: usize = 8;
+
+/// A peer that connected to us and whose handshake we answered
+#[derive(Debug)]
+pub struct InboundPeer {
+    pub addr: SocketAddr,
+    pub stream: TcpStream,
+    /// Handshake sent by the peer
+    pub handshake: Handshake,
+    /// Bytes the peer sent right behind its handshake
+    pub buffered: BytesMut,
+}
+
+/// Torrents accepting inbound peers, keyed by info hash
+type Registry = Mutex<HashMap<[u8; 20], mpsc::Sender<InboundPeer>>>;
+
+/// Accepts peer connections and hands them to the torrent they ask for
+pub struct Listener {
+    port: u16,
+    registry: Arc<Registry>,
+}
+
+impl Listener {
+    /// Listen on `port` of every interface, 0 picking a free port
+    pub async fn start(port: u16, peer_id: [u8; 20]) -> Result<Listener> {
+        // A dual-stack socket accepts IPv4 peers as well
+        let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => listener,
+            Err(_) => TcpListener::bind(("0.0.0.0", port)).await
+                .with_context(|| format!("Error listening on port {}", port))?,
+        };
+        let port = listener.local_addr()?.port();
+        let registry = Arc::new(Registry::default());
+        tokio::spawn(accept_loop(listener, registry.clone(), peer_id));
+        Ok(Listener { port, registry })
+    }
+
+    /// Port we actually listen on, to announce to trackers
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Receive the peers connecting for this torrent, until the receiver is dropped
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::Receiver<InboundPeer> {
+        let (tx, rx) = mpsc::channel(INBOUND_QUEUE);
+        self.registry.lock().unwrap().insert(info_hash, tx);
+        rx
+    }
+}
+
+async fn accept_loop(listener: TcpListener, registry: Arc<Registry>, peer_id: [u8; 20]) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                println!("Error accepting peer: {}", err);
+                continue;
+            }
+        };
+        // Dual-stack sockets report IPv4 peers as mapped IPv6 addresses
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(err) = accept(stream, addr, &registry, peer_id).await {
+                println!("Inbound peer {} rejected: {:#}", addr, err);
+            }
+        });
+    }
+}
+
+/// Answer the handshake of a peer if we serve the torrent it wants, then hand it over
+async fn accept(mut stream: TcpStream, addr: SocketAddr, registry: &Registry, peer_id: [u8; 20]) -> Result<()> {
+    let (handshake, buffered) = read_handshake(&mut stream).await?;
+    let info_hash = handshake.info_hash;
+    let torrent = registry.lock().unwrap().get(&info_hash).cloned()
+        .with_context(|| format!("Unknown info hash {}", hex::encode(info_hash)))?;
+
+    // A peer not reading our handshake, or a torrent with a full queue, must not hold the task
+    let handed_over = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        send_handshake(&mut stream, &Handshake::new(info_hash, peer_id)).await?;
+        anyhow::Ok(torrent.send(InboundPeer { addr, stream, handshake, buffered }).await.is_ok())
+    }).await.context("Peer was not taken over in time")??;
+    if !handed_over {
+        // The torrent is done with peers, forget about it
+        registry.lock().unwrap().remove(&info_hash);
+        anyhow::bail!("Torrent no longer accepts peers");
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+use anyhow::{bail, Context, Result};
+
+/// A `magnet:?xt=urn:btih:...` link
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name (`dn`)
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`)
+    pub trackers: Vec<String>,
+    /// Peer addresses (`x.pe`)
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> Result<Magnet> {
+        let query = link.strip_prefix("magnet:?").context("Magnet link must start with `magnet:?`")?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            // Query strings encode spaces as `+`, a literal `+` is `%2B`
+            let value = value.replace('+', " ");
+            let value = urlencoding::decode(&value)
+                .with_context(|| format!("Invalid percent-encoding in `{}`", pair))?
+                .into_owned();
+            match key {
+                "xt" => {
+                    // Other kinds of exact topics (e.g. btmh) are skipped
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => match value.parse::<SocketAddr>() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => println!("Ignoring invalid peer address {}", value),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.context("Magnet link has no `xt=urn:btih:` info hash")?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// The btih is either 40 hexadecimal characters or 32 base32 characters
+fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("Invalid hexadecimal info hash")?,
+        32 => base32_decode(hash).context("Invalid base32 info hash")?,
+        n => bail!("Info hash must be 40 hex or 32 base32 characters, got {}", n),
+    };
+    Ok(bytes.try_into().expect("info hash is 20 bytes long"))
+}
+
+/// Decode unpadded RFC 4648 base32
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: &str = "0e879aef2501e32cf6c0cc150f9769cc0a05861e";
+
+    #[test]
+    fn parse() {
+        let link = format!("magnet:?xt=urn:btih:{}&dn=a+b%2Bc&tr=http%3A%2F%2Ft%2Fannounce&x.pe=127.0.0.1:6881", INFO_HASH);
+        let magnet = Magnet::parse(&link).unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+        assert_eq!(magnet.name.as_deref(), Some("a b+c"));
+        assert_eq!(magnet.trackers, vec!["http://t/announce"]);
+        assert_eq!(magnet.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn hex_and_base32_info_hashes() {
+        let hex = Magnet::parse(&format!("magnet:?xt=urn:btih:{}", INFO_HASH)).unwrap();
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:B2DZV3ZFAHRSZ5WAZQKQ7F3JZQFALBQ6").unwrap();
+        let lowercase = Magnet::parse("magnet:?xt=urn:btih:b2dzv3zfahrsz5wazqkq7f3jzqfalbq6").unwrap();
+        assert_eq!(hex.info_hash, base32.info_hash);
+        assert_eq!(hex.info_hash, lowercase.info_hash);
+        assert!(Magnet::parse("magnet:?xt=urn:btih:B2DZV3ZFAHRSZ5WAZQKQ7F3JZQFALBQ1").is_err());
+        assert!(Magnet::parse("magnet:?dn=a").is_err());
+    }
+
+    #[test]
+    fn base32() {
+        assert_eq!(base32_decode("MZXW6YTBOI").as_deref(), Some(b"foobar".as_slice()));
+        assert_eq!(base32_decode("").as_deref(), Some(b"".as_slice()));
+        assert_eq!(base32_decode("MZ=="), None);
+    }
+}
@@ -1,39 +1,37 @@
-use tokio::net::TcpStream;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use crate::value::BencodeValue;
-use crate::torrent::Torrent;
+use bittorrent_starter_rust::torrent::{self, Keys, Torrent};
 
 
-use std::path::PathBuf;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio_util::codec::{Framed};
-use peers::Handshake;
-use tracker::{TrackerResponseSuccess, TrackerRequest};
-use crate::frame::MessageDecoder;
-use crate::peers::addr::Address;
-use futures_util::{StreamExt, SinkExt};
-use bytes::{BytesMut, BufMut};
-use crate::peers::{PeerMessage, PeerMessageType};
-
-const BLOCK_SIZE: u32 = 16 * 1024;
-
-mod decode;
-mod value;
-mod torrent;
-mod error;
-mod encode;
-mod peers;
-mod tracker;
-mod frame;
-
+use std::path::{Path, PathBuf};
+use bittorrent_starter_rust::announcer::{Announcer, AnnouncerHandle, TransferStats};
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::listener::{self, Listener};
+use bittorrent_starter_rust::peers::{self, generate_peer_id, make_handshake};
+use bittorrent_starter_rust::tracker::{TrackerManager, TrackerRequest};
+use bittorrent_starter_rust::scheduler::Scheduler;
+use bittorrent_starter_rust::storage::Storage;
+use bittorrent_starter_rust::upload::{self, PieceStore};
+use bittorrent_starter_rust::magnet::Magnet;
+use bittorrent_starter_rust::{de, decode, download, metadata};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Peer id to use instead of a random one, exactly 20 bytes
+    #[clap(long, global = true, value_parser = parse_peer_id)]
+    peer_id: Option<[u8; 20]>,
+    /// Port to accept peer connections on, 0 for any free port
+    #[clap(long, global = true, default_value_t = listener::DEFAULT_PORT)]
+    port: u16,
+}
+
+fn parse_peer_id(value: &str) -> Result<[u8; 20], String> {
+    value.as_bytes().try_into().map_err(|_| format!("peer id must be 20 bytes long, got {}", value.len()))
 }
 
 #[derive(Subcommand)]
@@ -57,8 +55,43 @@ enum Commands {
         output: PathBuf,
         file: PathBuf,
         piece_index: u32,
+        /// Number of block requests kept in flight per peer
+        #[clap(long, default_value_t = download::DEFAULT_PIPELINE)]
+        pipeline: usize,
 
     },
+    Download {
+        #[clap(short, long)]
+        output: PathBuf,
+        file: PathBuf,
+        /// Number of block requests kept in flight per peer
+        #[clap(long, default_value_t = download::DEFAULT_PIPELINE)]
+        pipeline: usize,
+    },
+    /// Serve the data of a torrent, already on disk at `path`, until interrupted
+    Seed {
+        file: PathBuf,
+        path: PathBuf,
+    },
+    /// Print seeders, leechers and completed downloads of torrents
+    Scrape {
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+    },
+    MagnetParse {
+        link: String,
+    },
+    MagnetInfo {
+        link: String,
+    },
+    MagnetDownload {
+        #[clap(short, long)]
+        output: PathBuf,
+        link: String,
+        /// Number of block requests kept in flight per peer
+        #[clap(long, default_value_t = download::DEFAULT_PIPELINE)]
+        pipeline: usize,
+    },
 }
 
 // Usage: your_bittorrent.sh decode "<encoded_value>"
@@ -70,13 +103,15 @@ async fn main() -> Result<()> {
     // Declare the data that we will need to send to the tracker
     let mut info_hash = [0; 20];
     let mut torrent = Torrent::new();
-    let peer_id = "00112233445566778899".to_string();
+    // One peer id for the whole session, sent to trackers and peers alike
+    let peer_id = cli.peer_id.unwrap_or_else(generate_peer_id);
+    let port = cli.port;
 
 
     match cli.command {
         Commands::Decode { encoded_value } => {
             let encoded_bytes = encoded_value.as_bytes();
-            let mut parser = decode::Parser::new(&encoded_bytes);
+            let mut parser = decode::Parser::new(encoded_bytes);
             match parser.parse() {
                 Ok(decoded_value) => {
                     println!("{}", decoded_value);
@@ -101,7 +136,8 @@ async fn main() -> Result<()> {
             // Read the file
             let content: &[u8] = &std::fs::read(file)?;
             read_info(content, &mut info_hash, &mut torrent, true)?;
-            make_peer_request(&info_hash, &torrent, peer_id).await.context("Error making peer request")?;
+            let mut trackers = TrackerManager::new(torrent.trackers());
+            make_peer_request(&mut trackers, &info_hash, torrent.length(), peer_id, port).await.context("Error making peer request")?;
             Ok(())
         }
         Commands::Handshake {
@@ -112,210 +148,324 @@ async fn main() -> Result<()> {
             let content: &[u8] = &std::fs::read(file)?;
             let socket_addr = socket_addr.parse::<SocketAddr>().context("Error parsing socket address")?;
             read_info(content, &mut info_hash, &mut torrent, false)?;
-            let mut stream = TcpStream::connect(socket_addr).await?;
-            make_handshake(&mut stream, &info_hash).await.context("Error making handshake")?;
+            let mut stream = peers::connect(socket_addr).await?;
+            let (handshake, _) = make_handshake(&mut stream, &info_hash, &peer_id).await.context("Error making handshake")?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
             Ok(())
         }
         Commands::DownloadPiece {
-            output: _output,
+            output,
             file,
             piece_index,
+            pipeline,
         } => {
             // Read the file
             let content: &[u8] = &std::fs::read(file)?;
 
             read_info(content, &mut info_hash, &mut torrent, false)?;
-            let peers = make_peer_request(&info_hash, &torrent, peer_id).await.context("Error making peer request")?;
-            let mut stream = TcpStream::connect(&peers[..]).await?;
-            make_handshake(&mut stream, &info_hash).await.context("Error making handshake")?;
-
-            //
-            let mut framed = Framed::new(stream, MessageDecoder);
-            let message_bitfield =  framed.next().await.expect("Expect a bitfield message").context("Error reading bitfield message")?;
-            assert_eq!(message_bitfield.id, PeerMessageType::Bitfield);
-
-            // Send an interested message
-            framed.send(PeerMessage {
-                id: PeerMessageType::Interested,
-                length: 1,
-                payload: vec![],
-            }).await.expect("Error sending interested message");
-
-            let unchoke = framed.next().await.expect("Expect a unchoke").context("Error reading unchoke")?;
-            assert_eq!(unchoke.id, PeerMessageType::Unchoke);
-
-            let length = torrent.info.length;
-            println!("Length: {}", length);
-            let piece_length = torrent.info.piece_length;
-            println!("Piece length: {}", piece_length);
-
-            let piece_size = torrent.info.piece_length as u32;
-            let mut all_blocks: Vec<u8> = Vec::with_capacity(piece_size as usize);
-
-            let nblocks = (piece_size + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
-            println!("Number of blocks: {}", nblocks);
-            println!("Block size: {}", BLOCK_SIZE);
-
-            for block in 0..nblocks {
-                let offset = block * BLOCK_SIZE as u32;
-                let length = std::cmp::min(BLOCK_SIZE, piece_size - offset);
-                let mut payload = BytesMut::with_capacity(12);
-
-                // Print data
-                println!("Request block: {}, Offset: {}, Length: {}", block, offset, length);
-
-                // Add data to request payload
-                payload.put_u32(piece_index);
-                payload.put_u32(offset);
-                payload.put_u32(length);
-
-                let request = PeerMessage {
-                    id: PeerMessageType::Request,
-                    length: 13,
-                    payload: payload.to_vec(),
-                };
-                framed.send(request).await.with_context(|| format!("Error sending request for block {}", block))?;
-
-                let piece = framed
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("Piece message was invalid")?;
-                assert_eq!(piece.id, PeerMessageType::Piece);
-                assert!(!piece.payload.is_empty());
+            let mut trackers = TrackerManager::new(torrent.trackers());
+            let peers = make_peer_request(&mut trackers, &info_hash, torrent.length(), peer_id, port).await.context("Error making peer request")?;
 
-                println!("Piece payload length: {}", piece.payload.len());
-                // println!("Piece payload: {:?}", piece.payload);
+            let mut pieces = download::download_pieces(peers, Arc::new(torrent), &info_hash, &peer_id, vec![piece_index], pipeline).await
+                .with_context(|| format!("Error downloading piece {}", piece_index))?;
+            let (_, piece) = pieces.pop().context("Piece was not downloaded")?;
 
-                // Split the payload bytes to get the index, offset, and data
-                let (index_bytes, rest) = piece.payload.split_at(4);
-                let (offset_bytes, data) = rest.split_at(4);
-
-                let received_index = u32::from_be_bytes([index_bytes[0], index_bytes[1], index_bytes[2], index_bytes[3]]);
-                let received_offset = u32::from_be_bytes([offset_bytes[0], offset_bytes[1], offset_bytes[2], offset_bytes[3]]);
-                println!("Received index: {}, offset: {}", received_index, received_offset);
-                //println!("Received data: {:?}", data);
-
-                assert_eq!(received_index, piece_index);
-                assert_eq!(received_offset, offset);
-                assert_eq!(data.len(), length as usize);
-                all_blocks.extend_from_slice(data);
+            std::fs::write(&output, piece).context("Error writing piece to output file")?;
+            println!("Piece {} downloaded to {}.", piece_index, output.display());
+            Ok(())
+        }
+        Commands::Download {
+            output,
+            file,
+            pipeline,
+        } => {
+            // Read the file
+            let content: &[u8] = &std::fs::read(file)?;
 
+            read_info(content, &mut info_hash, &mut torrent, false)?;
+            let trackers = TrackerManager::new(torrent.trackers());
+            run_download(torrent, info_hash, trackers, Vec::new(), peer_id, port, &output, pipeline).await
+        }
+        Commands::Seed {
+            file,
+            path,
+        } => {
+            let content: &[u8] = &std::fs::read(file)?;
+            read_info(content, &mut info_hash, &mut torrent, false)?;
+            let trackers = TrackerManager::new(torrent.trackers());
+            run_seed(torrent, info_hash, trackers, peer_id, port, &path).await
+        }
+        Commands::Scrape {
+            files,
+        } => {
+            let mut torrents = Vec::new();
+            for file in files {
+                let content: &[u8] = &std::fs::read(&file).with_context(|| format!("Error reading {}", file.display()))?;
+                read_info(content, &mut info_hash, &mut torrent, false)?;
+                let tiers = torrent.trackers();
+                if tiers.is_empty() {
+                    println!("{}: no tracker", file.display());
+                } else {
+                    torrents.push((tiers, info_hash, torrent.info.name.clone()));
+                }
             }
 
+            // Torrents sharing their trackers are scraped with a single request
+            let mut groups: Vec<&Vec<Vec<String>>> = torrents.iter().map(|(tiers, _, _)| tiers).collect();
+            groups.sort();
+            groups.dedup();
+            for tiers in groups {
+                let scraped: Vec<_> = torrents.iter().filter(|(group, _, _)| group == tiers).collect();
+                let info_hashes: Vec<[u8; 20]> = scraped.iter().map(|(_, info_hash, _)| *info_hash).collect();
+                let (tracker, stats) = match TrackerManager::new(tiers.clone()).scrape(&info_hashes).await {
+                    Ok(scrape) => scrape,
+                    Err(err) => {
+                        println!("Error scraping {}: {:#}", tiers.concat().join(" "), err);
+                        continue;
+                    }
+                };
+                for (_, info_hash, name) in scraped {
+                    match stats.iter().find(|stats| stats.info_hash == *info_hash) {
+                        Some(stats) => println!("{} {}: {} seeders, {} leechers, {} downloaded",
+                            hex::encode(info_hash), name, stats.complete, stats.incomplete, stats.downloaded),
+                        None => println!("{} {}: unknown to {}", hex::encode(info_hash), name, tracker),
+                    }
+                }
+            }
+            Ok(())
+        }
+        Commands::MagnetParse {
+            link,
+        } => {
+            let magnet = Magnet::parse(&link)?;
+            if let Some(name) = &magnet.name {
+                println!("Name: {}", name);
+            }
+            for tracker in &magnet.trackers {
+                println!("Tracker URL: {}", tracker);
+            }
+            for peer in &magnet.peers {
+                println!("Peer: {}", peer);
+            }
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+            Ok(())
+        }
+        Commands::MagnetInfo {
+            link,
+        } => {
+            let magnet = Magnet::parse(&link)?;
+            let mut trackers = magnet_trackers(&magnet);
+            let peers = magnet_peers(&magnet, &mut trackers, peer_id, port).await?;
+            let torrent = magnet_torrent(&magnet, &peers, &peer_id).await?;
+            print_info(&torrent, &magnet.info_hash);
             Ok(())
-            // todo!("Merge each downloaded block");
+        }
+        Commands::MagnetDownload {
+            output,
+            link,
+            pipeline,
+        } => {
+            let magnet = Magnet::parse(&link)?;
+            let mut trackers = magnet_trackers(&magnet);
+            let peers = magnet_peers(&magnet, &mut trackers, peer_id, port).await?;
+            let torrent = magnet_torrent(&magnet, &peers, &peer_id).await?;
+            run_download(torrent, magnet.info_hash, trackers, peers, peer_id, port, &output, pipeline).await
         }
     }
 }
 
-async fn make_handshake(stream: &mut TcpStream, info_hash: &[u8; 20]) -> Result<()> {
-    let handshake = Handshake::new(*info_hash, *b"00112233445566778899");
-
-    //let handshake_bytes_size = std::mem::size_of::<Handshake>();
-    //println!("Handshake size: {}", handshake_bytes_size);
-    let serialized_bytes = bincode::serialize(&handshake).expect("Serialization failed for handshake");
-    //println!("Serialized: {:?}", serialized_bytes);
-    stream.write_all(&serialized_bytes).await.expect("Error writing to stream");
-
-
-    // Read the current data from the stream
-    let mut reader = BufReader::new(stream);
-    let received: Vec<u8> = reader.fill_buf().await.expect("Error reading from stream").to_vec();
-    //println!("Received length: {}", received.len());
-    let handshake_response: Handshake = bincode::deserialize(&received).expect("Error deserializing handshake");
-    println!("Peer ID: {}", handshake_response.peer_id.iter().map(|b| format!("{:02x}", b)).collect::<String>());
-    assert_eq!(handshake_response.p_str, *b"BitTorrent protocol");
-    assert_eq!(handshake_response.length, 19);
-    // Consume the buffer
-    reader.consume(received.len());
+/// Download the whole torrent, keeping its trackers informed from start to finish.
+/// `peers` are known already, the trackers may be unreachable if there are any.
+#[allow(clippy::too_many_arguments)]
+async fn run_download(torrent: Torrent, info_hash: [u8; 20], trackers: TrackerManager, mut peers: Vec<SocketAddr>, peer_id: [u8; 20], port: u16, output: &Path, pipeline: usize) -> Result<()> {
+    // Without a listener we can still download, only from the peers we connect to
+    let (port, inbound) = match Listener::start(port, peer_id).await {
+        Ok(listener) => (listener.port(), Some(listener.register(info_hash))),
+        Err(err) => {
+            println!("Not accepting peer connections: {:#}", err);
+            (port, None)
+        }
+    };
+    let stats = Arc::new(TransferStats::new(torrent.length()));
+    let announcer = start_announcer(trackers, info_hash, peer_id, port, stats.clone(), &mut peers).await?;
+    for peer in &peers {
+        println!("{}", peer);
+    }
+
+    let name = torrent.info.name.clone();
+    let storage = Storage::new(&torrent, output)?;
+    let pieces = 0..torrent.piece_count();
+    let torrent = Arc::new(torrent);
+    // Pieces we have are served to the other peers while downloading
+    let have = Bitfield::with_pieces(torrent.piece_count() as usize);
+    let store = Arc::new(PieceStore::new(torrent.clone(), storage, stats, have));
+    let scheduler = Scheduler::new(torrent, info_hash, peer_id, pieces, pipeline, Some(store.clone()));
+    let result = tokio::select! {
+        result = download::download_all(peers, inbound, scheduler, &store) => result,
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("Interrupted")),
+    };
+    if let Some(announcer) = announcer {
+        if result.is_ok() {
+            announcer.completed();
+        }
+        announcer.stop().await;
+    }
+    result?;
+
+    println!("Downloaded {} to {}.", name, output.display());
     Ok(())
 }
 
-fn read_info(content: &[u8], info_hash: &mut [u8; 20], torrent: &mut Torrent, print: bool) -> Result<()> {
-    let mut parser = decode::Parser::new(&content);
-    match parser.parse() {
-        Ok(decoded_value) => {
-            if let BencodeValue::BDictionary(map) = decoded_value {
-                if let Some(url) = map.get("announce".as_bytes()) {
-                    let url_string = format!("{}", url);
-                    let output = url_string.trim_matches('"'); // Remove quotes
-                    torrent.announce = output.to_string();
-                    if print { println!("Tracker URL: {}", output); }
+/// Serve the verified pieces of a torrent found at `path` until interrupted
+async fn run_seed(torrent: Torrent, info_hash: [u8; 20], trackers: TrackerManager, peer_id: [u8; 20], port: u16, path: &Path) -> Result<()> {
+    let storage = Storage::open(&torrent, path)?;
+    let have = PieceStore::check(&torrent, &storage)?;
+    let pieces: Vec<u32> = (0..torrent.piece_count()).filter(|&index| have.has(index)).collect();
+    if pieces.is_empty() {
+        anyhow::bail!("No valid piece of {} found in {}", torrent.info.name, path.display());
+    }
+    println!("Seeding {} of {} pieces", pieces.len(), torrent.piece_count());
+
+    // Seeding is all about peers connecting to us
+    let listener = Listener::start(port, peer_id).await?;
+    let inbound = listener.register(info_hash);
+    let port = listener.port();
+    println!("Listening on port {}", port);
+
+    let missing: u64 = (0..torrent.piece_count())
+        .filter(|&index| !have.has(index))
+        .map(|index| torrent.piece_size(index) as u64)
+        .sum();
+    let stats = Arc::new(TransferStats::new(missing));
+    let mut peers = Vec::new();
+    // Peers that know about us already may still connect when the trackers are down
+    let announcer = match start_announcer(trackers, info_hash, peer_id, port, stats.clone(), &mut peers).await {
+        Ok(announcer) => announcer,
+        Err(err) => {
+            println!("{:#}", err);
+            None
+        }
+    };
+
+    let store = Arc::new(PieceStore::new(Arc::new(torrent), storage, stats.clone(), have));
+    tokio::select! {
+        _ = upload::seed(store, peers, inbound, info_hash, peer_id) => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    if let Some(announcer) = announcer {
+        announcer.stop().await;
+    }
+    println!("Uploaded {} bytes.", stats.uploaded());
+    Ok(())
+}
+
+/// Announce the start of a transfer and add the peers of the trackers to `peers`.
+/// Trackers may be unreachable as long as some peers are known already.
+async fn start_announcer(trackers: TrackerManager, info_hash: [u8; 20], peer_id: [u8; 20], port: u16, stats: Arc<TransferStats>, peers: &mut Vec<SocketAddr>) -> Result<Option<AnnouncerHandle>> {
+    let announcer = Announcer::new(trackers, info_hash, peer_id, port, stats);
+    match announcer.start().await {
+        Ok((response, handle)) => {
+            for peer in response.peers {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
                 }
+            }
+            Ok(Some(handle))
+        }
+        Err(err) if !peers.is_empty() => {
+            println!("Error announcing to trackers: {:#}", err);
+            Ok(None)
+        }
+        Err(err) => Err(err.context("Error making peer request")),
+    }
+}
 
-                if let Some(info) = map.get("info".as_bytes()) {
-                    if let BencodeValue::BDictionary(map) = info {
-                        if let Some(length) = map.get("length".as_bytes()) {
-                            if print { println!("Length: {}", length); }
-                            torrent.info.length = length.to_string().parse::<i64>().unwrap();
-                        }
-
-                        if let Some(length) = map.get("piece length".as_bytes()) {
-                            if print { println!("Piece Length: {}", length); }
-                            torrent.info.piece_length = length.to_string().parse::<i64>().unwrap();
-                        }
-
-                        let mut encoder = encode::Encoder::new();
-                        encoder.encode(info)?;
-                        let hash = encoder.encode_sha1(info_hash);
-                        if print { println!("Info Hash: {}", hash); }
-
-
-                        if let Some(pieces_string) = map.get("pieces".as_bytes()) {
-                            // Get the bytes string and represent as hexadecimal
-                            // Represent hexadecimal hash of each piece
-                            if let BencodeValue::BString(pieces_string) = pieces_string {
-                                if print { println!("Piece Hashes:"); }
-                                let mut remaining_hash_data = &pieces_string[..];
-                                while !remaining_hash_data.is_empty() {
-                                    let (hash, rest) = remaining_hash_data.split_at(20);
-                                    remaining_hash_data = rest;
-                                    let hash_in_hex = hex::encode(hash);
-                                    if print { println!("{}", hash_in_hex); }
-                                }
-                            }
-                        }
+/// Trackers of a magnet link, all in one tier as every one of them is as good as the others
+fn magnet_trackers(magnet: &Magnet) -> TrackerManager {
+    TrackerManager::new(vec![magnet.trackers.clone()])
+}
+
+/// Gather peers from the first tracker of the magnet link answering along with the peers it lists
+async fn magnet_peers(magnet: &Magnet, trackers: &mut TrackerManager, peer_id: [u8; 20], port: u16) -> Result<Vec<SocketAddr>> {
+    let mut peers = magnet.peers.clone();
+    if !magnet.trackers.is_empty() {
+        // The size of the torrent is unknown until we get the metadata
+        match make_peer_request(trackers, &magnet.info_hash, 1, peer_id, port).await {
+            Ok(tracker_peers) => {
+                for peer in tracker_peers {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
                     }
                 }
             }
-            Ok(())
+            Err(err) => println!("Error requesting peers: {:#}", err),
         }
-        Err(err) => {
-            println!("Error decoding info: {}", err);
-            Err(err.into())
+    }
+    if peers.is_empty() {
+        anyhow::bail!("No peers found for magnet link");
+    }
+    Ok(peers)
+}
+
+/// Build the torrent of a magnet link from the metadata sent by its peers
+async fn magnet_torrent(magnet: &Magnet, peers: &[SocketAddr], peer_id: &[u8; 20]) -> Result<Torrent> {
+    let metadata = metadata::fetch_metadata(peers, &magnet.info_hash, peer_id).await?;
+    let info: torrent::Info = de::from_bytes(&metadata).context("Invalid metadata")?;
+    info.check().context("Invalid metadata")?;
+    Ok(Torrent {
+        info,
+        announce: magnet.trackers.first().cloned().unwrap_or_default(),
+        // Every tracker of the link is as good as the others
+        announce_list: if magnet.trackers.is_empty() { Vec::new() } else { vec![magnet.trackers.clone()] },
+    })
+}
+
+fn read_info(content: &[u8], info_hash: &mut [u8; 20], torrent: &mut Torrent, print: bool) -> Result<()> {
+    *torrent = Torrent::from_bytes(content)?;
+    *info_hash = torrent::info_hash(content)?;
+    if print {
+        print_info(torrent, info_hash);
+    }
+    Ok(())
+}
+
+fn print_info(torrent: &Torrent, info_hash: &[u8; 20]) {
+    println!("Tracker URL: {}", torrent.announce);
+    for (tier, trackers) in torrent.announce_list.iter().enumerate() {
+        println!("Tracker Tier {}: {}", tier, trackers.join(" "));
+    }
+    match &torrent.info.keys {
+        Keys::SingleFile { length } => println!("Length: {}", length),
+        Keys::MultiFile { files } => {
+            for file in files {
+                println!("File: {} ({} bytes)", file.path.join("/"), file.length);
+            }
         }
     }
+    println!("Piece Length: {}", torrent.info.piece_length);
+    println!("Info Hash: {}", hex::encode(info_hash));
+
+    // Represent hexadecimal hash of each piece
+    println!("Piece Hashes:");
+    for hash in &torrent.info.pieces.0 {
+        println!("{}", hex::encode(hash));
+    }
 }
 
-async fn make_peer_request(info_hash: &[u8; 20], torrent: &Torrent, peer_id: String) -> Result<Vec<SocketAddr>> {
+async fn make_peer_request(trackers: &mut TrackerManager, info_hash: &[u8; 20], left: u64, peer_id: [u8; 20], port: u16) -> Result<Vec<SocketAddr>> {
     let d = TrackerRequest::default();
-    const PORT: u16 = 6881;
 
-    // URL encode the byte string
     let tracker_request = TrackerRequest {
         peer_id,
-        left: torrent.info.length as u64,
-        port: PORT,
+        left,
+        port,
         ..d
     };
-    // This cannot be urlencoded by serialize, it goes apart
-    let encoded_info_hash = url_encode(info_hash);
-    // println!("{:#?}", tracker_request);
-    let encoded_request = serde_urlencoded::to_string(tracker_request).context("Error encoding tracker request")?;
-    // println!("Encoded request: {}", encoded_request);
-
-    // Make request to tracker url
-    let url = format!("{}?{}&info_hash={}", torrent.announce, encoded_request, encoded_info_hash);
-    println!("URL: {}", url);
-    let client = reqwest::Client::new().get(url);
-    let response_bytes = client
-        .send()
-        .await?
-        .bytes().await?;
-    let TrackerResponseSuccess {
-        peers: Address(list_peers), ..
-    } = serde_bencode::from_bytes(&response_bytes).context("Error decoding serde response")?;
+    let response = trackers.announce(info_hash, tracker_request).await?;
+    if let Some(warning) = &response.warning {
+        println!("Tracker warning: {}", warning);
+    }
+    let list_peers = response.peers;
 
     for peer in list_peers.clone() {
         println!("{}", peer);
@@ -323,11 +473,3 @@ async fn make_peer_request(info_hash: &[u8; 20], torrent: &Torrent, peer_id: Str
 
     Ok(list_peers)
 }
-
-pub fn url_encode(info_hash: &[u8; 20]) -> String {
-    let mut url_encoded = String::new();
-    for byte in info_hash {
-        url_encoded.push_str(&format!("%{:02x}", byte));
-    }
-    url_encoded
-}
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use linked_hash_map::LinkedHashMap;
+use sha1::{Digest, Sha1};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+use crate::decode::Parser;
+use crate::encode::Encoder;
+use crate::extension::{self, ExtensionHandshake, ExtensionRegistry};
+use crate::frame::MessageDecoder;
+use crate::peers::{self, make_handshake, PeerMessage};
+use crate::value::BencodeValue;
+
+/// Name of the metadata exchange extension in the extension handshake
+pub const UT_METADATA: &str = "ut_metadata";
+/// Metadata is exchanged in pieces of 16 KiB (BEP 9)
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Refuse metadata larger than this
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+/// Time the peer has to send its extension handshake, then each metadata piece
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+/// Fetch the `info` dictionary from the first peer able to send it, verified against the info hash
+pub async fn fetch_metadata(peers: &[SocketAddr], info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<Vec<u8>> {
+    for &addr in peers {
+        match fetch_metadata_from(addr, info_hash, peer_id).await {
+            Ok(metadata) => return Ok(metadata),
+            Err(err) => println!("Error fetching metadata from peer {}: {:#}", addr, err),
+        }
+    }
+    bail!("No peer sent the torrent metadata")
+}
+
+/// Fetch the `info` dictionary from a single peer using the `ut_metadata` extension (BEP 9)
+pub async fn fetch_metadata_from(addr: SocketAddr, info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<Vec<u8>> {
+    let mut stream = peers::connect(addr).await?;
+    let (response, buffered) = make_handshake(&mut stream, info_hash, peer_id).await.context("Error making handshake")?;
+    if !response.supports_extensions() {
+        bail!("Peer does not support the extension protocol");
+    }
+
+    let mut registry = ExtensionRegistry::new();
+    let ut_metadata_id = registry.register(UT_METADATA);
+    let mut framed = MessageDecoder::framed(stream, buffered);
+    framed.send(extension::extended_message(extension::HANDSHAKE_ID, &registry.handshake().to_bytes()?)).await
+        .context("Error sending extension handshake")?;
+
+    // Wait for the peer extension handshake, skipping bitfield and have messages
+    let peer_handshake = tokio::time::timeout(EXCHANGE_TIMEOUT, async {
+        loop {
+            let (id, payload) = next_extended(&mut framed).await?;
+            if id == extension::HANDSHAKE_ID {
+                return ExtensionHandshake::from_bytes(&payload);
+            }
+        }
+    }).await.context("Peer did not send its extension handshake")??;
+    let peer_metadata_id = peer_handshake.id_of(UT_METADATA).context("Peer does not support ut_metadata")?;
+    let metadata_size = peer_handshake.metadata_size.context("Peer did not send metadata_size")?;
+    if metadata_size <= 0 || metadata_size as usize > MAX_METADATA_SIZE {
+        bail!("Invalid metadata size {}", metadata_size);
+    }
+    let metadata_size = metadata_size as usize;
+
+    let mut metadata = Vec::with_capacity(metadata_size);
+    for piece in 0..metadata_size.div_ceil(METADATA_PIECE_SIZE) {
+        let mut request = LinkedHashMap::new();
+        request.insert(b"msg_type".to_vec(), BencodeValue::BInteger(MSG_REQUEST));
+        request.insert(b"piece".to_vec(), BencodeValue::BInteger(piece as i64));
+        let mut encoder = Encoder::new();
+        encoder.encode(&BencodeValue::BDictionary(request))?;
+        framed.send(extension::extended_message(peer_metadata_id, &encoder.into_bytes())).await
+            .context("Error requesting metadata piece")?;
+
+        let received = tokio::time::timeout(EXCHANGE_TIMEOUT, async {
+            loop {
+                let (id, payload) = next_extended(&mut framed).await?;
+                if id != ut_metadata_id {
+                    continue;
+                }
+                let mut parser = Parser::new(&payload);
+                let BencodeValue::BDictionary(dict) = parser.parse()? else {
+                    bail!("ut_metadata message is not a dictionary");
+                };
+                match int_get(&dict, b"msg_type") {
+                    // Metadata pieces are appended right after the dictionary
+                    Some(MSG_DATA) if int_get(&dict, b"piece") == Some(piece as i64) => return Ok(payload.slice(parser.position()..)),
+                    Some(MSG_REJECT) => bail!("Peer rejected metadata piece {}", piece),
+                    _ => continue,
+                }
+            }
+        }).await.with_context(|| format!("Peer did not send metadata piece {}", piece))??;
+
+        let expected = std::cmp::min(METADATA_PIECE_SIZE, metadata_size - metadata.len());
+        if received.len() != expected {
+            bail!("Metadata piece {} has {} bytes, expected {}", piece, received.len(), expected);
+        }
+        metadata.extend_from_slice(&received);
+    }
+
+    if Sha1::digest(&metadata).as_slice() != info_hash {
+        bail!("Metadata does not match the info hash");
+    }
+    Ok(metadata)
+}
+
+/// Read messages until an extended one arrives, returning its extended id and payload
+async fn next_extended(framed: &mut Framed<TcpStream, MessageDecoder>) -> Result<(u8, Bytes)> {
+    loop {
+        let message = framed.next().await.context("Peer closed the connection")?.context("Invalid message")?;
+        if let PeerMessage::Extended { id, payload } = message {
+            return Ok((id, payload));
+        }
+    }
+}
+
+fn int_get(dict: &LinkedHashMap<Vec<u8>, BencodeValue>, key: &[u8]) -> Option<i64> {
+    match dict.get(key) {
+        Some(BencodeValue::BInteger(value)) => Some(*value),
+        _ => None,
+    }
+}
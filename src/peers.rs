@@ -1,26 +1,38 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use bytes::{Bytes, BytesMut};
 use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::bitfield::Bitfield;
+use crate::error::HandshakeError;
+use crate::random::random_u64;
 
-#[repr(u8)]
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub enum PeerMessageType {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
-    KeepAlive = 9,
-}
-
-#[derive(Debug)]
-#[repr(C)]
-pub struct PeerMessage {
-    pub length: u32,
-    pub id: PeerMessageType,
-    pub payload: Vec<u8>,
+/// A message of the peer wire protocol, framed by `frame::MessageDecoder`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerMessage {
+    /// Zero-length frame keeping the connection open, it has no id
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Bytes },
+    Cancel { index: u32, begin: u32, length: u32 },
+    /// Fast Extension messages (BEP 6)
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+    /// Extension protocol message (BEP 10) with its extended message id
+    Extended { id: u8, payload: Bytes },
+    /// Message we do not support, kept with its id so it can be skipped
+    Unknown(u8, Bytes),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,33 +45,141 @@ pub struct Handshake {
 }
 
 
+/// Length of a handshake with the standard protocol string
+pub const HANDSHAKE_LEN: usize = 68;
+/// Peers must complete their handshake within this delay
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Give up on peers that do not accept our connection within this delay
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bit of the reserved bytes advertising the extension protocol (BEP 10)
+pub const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+/// Bit of the reserved bytes advertising the Fast Extension (BEP 6)
+pub const FAST_BIT: (usize, u8) = (7, 0x04);
+
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_BIT.0] |= EXTENSION_BIT.1;
+        reserved[FAST_BIT.0] |= FAST_BIT.1;
         Handshake {
             length: 19,
             p_str: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
+    }
+
+    /// Whether the peer supports the Fast Extension, which we always do
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_BIT.0] & FAST_BIT.1 != 0
+    }
+}
+
+/// Azureus-style client prefix of our peer ids: client code and version between dashes
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-BR0001-";
+
+/// A new peer id: our client prefix followed by 12 random alphanumeric characters
+pub fn generate_peer_id() -> [u8; 20] {
+    const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut peer_id = [0; 20];
+    peer_id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX);
+    for byte in &mut peer_id[PEER_ID_PREFIX.len()..] {
+        *byte = CHARSET[(random_u64() % CHARSET.len() as u64) as usize];
+    }
+    peer_id
+}
+
+/// Connect to a peer, giving up after `CONNECT_TIMEOUT`
+pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(stream) => stream,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Connecting to {} timed out", addr))),
+    }
+}
+
+/// Send our handshake and read the peer's answer, which must be for the same torrent.
+/// Returns it along with the bytes the peer sent right behind it, for the message codec.
+pub async fn make_handshake(stream: &mut TcpStream, info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<(Handshake, BytesMut), HandshakeError> {
+    let exchange = async {
+        send_handshake(stream, &Handshake::new(*info_hash, *peer_id)).await?;
+        let (handshake, buffered) = receive_handshake(stream).await?;
+        if handshake.info_hash != *info_hash {
+            return Err(HandshakeError::InfoHashMismatch { expected: *info_hash, received: handshake.info_hash });
+        }
+        Ok((handshake, buffered))
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange).await.map_err(|_| HandshakeError::Timeout)?
+}
+
+/// Read the handshake of a peer that connected to us, along with the bytes it sent right behind it
+pub async fn read_handshake(stream: &mut TcpStream) -> Result<(Handshake, BytesMut), HandshakeError> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, receive_handshake(stream)).await.map_err(|_| HandshakeError::Timeout)?
+}
+
+pub async fn send_handshake(stream: &mut TcpStream, handshake: &Handshake) -> Result<(), HandshakeError> {
+    let bytes = bincode::serialize(handshake).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn receive_handshake(stream: &mut TcpStream) -> Result<(Handshake, BytesMut), HandshakeError> {
+    // The peer may send its bitfield right behind the handshake, whatever is read past it is kept
+    let mut buffer = BytesMut::with_capacity(HANDSHAKE_LEN);
+    while buffer.len() < HANDSHAKE_LEN {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(HandshakeError::Closed);
+        }
+    }
+    let received = buffer.split_to(HANDSHAKE_LEN);
+    if received[0] != 19 || received[1..20] != *b"BitTorrent protocol" {
+        return Err(HandshakeError::InvalidProtocol);
+    }
+    let handshake = bincode::deserialize(&received).map_err(|_| HandshakeError::InvalidProtocol)?;
+    Ok((handshake, buffer))
 }
 
 pub mod addr {
     use std::fmt;
-    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
     use serde::{Deserialize, Deserializer};
-    use serde::de::Visitor;
+    use serde::de::{SeqAccess, Visitor};
 
+    /// Peers of the `peers` key: compact IPv4 string or list of dictionaries
     #[derive(Debug, Clone)]
     pub struct Address(pub Vec<SocketAddr>);
 
+    /// Peers of the `peers6` key: compact IPv6 string (BEP 7)
+    #[derive(Debug, Clone)]
+    pub struct Address6(pub Vec<SocketAddr>);
+
+    /// A peer of a non-compact list. The `peer id` key is ignored, the handshake tells it anyway.
+    #[derive(Debug, Deserialize)]
+    struct PeerEntry {
+        ip: String,
+        port: u16,
+    }
+
     impl<'de> Deserialize<'de> for Address {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
             where
                 D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeerVisitor)
+            deserializer.deserialize_any(PeerVisitor)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Address6 {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(Peer6Visitor)
         }
     }
 
@@ -70,14 +190,14 @@ pub mod addr {
 
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a peer")
+            formatter.write_str("a compact peer string or a list of peer dictionaries")
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
             where
                 E: serde::de::Error,
         {
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom(format!("bytes length error for peers {}", v.len())));
             }
             Ok(Address(
@@ -91,9 +211,51 @@ pub mod addr {
                     .collect(),
             ))
         }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(PeerEntry { ip, port }) = seq.next_element()? {
+                // Host names are not resolved, trackers are expected to send addresses
+                if let Ok(ip) = ip.parse::<IpAddr>() {
+                    peers.push(SocketAddr::new(ip, port));
+                }
+            }
+            Ok(Address(peers))
+        }
     }
-}
 
+    struct Peer6Visitor;
 
+    impl<'de> Visitor<'de> for Peer6Visitor {
+        type Value = Address6;
 
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a compact IPv6 peer string")
+        }
 
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+        {
+            if !v.len().is_multiple_of(18) {
+                return Err(E::custom(format!("bytes length error for peers6 {}", v.len())));
+            }
+            Ok(Address6(
+                v.chunks_exact(18)
+                    .map(|slice_18| {
+                        let ip: [u8; 16] = slice_18[..16].try_into().expect("chunk is 18 bytes long");
+                        SocketAddr::V6(SocketAddrV6::new(
+                            Ipv6Addr::from(ip),
+                            u16::from_be_bytes([slice_18[16], slice_18[17]]),
+                            0,
+                            0,
+                        ))
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use anyhow::{bail, Result};
+use tokio::sync::{mpsc, Notify, Semaphore};
+use crate::bitfield::Bitfield;
+use crate::download::{download_piece, verify_piece};
+use crate::extension::ExtensionRegistry;
+use crate::listener::InboundPeer;
+use crate::session::PeerSession;
+use crate::upload::{PieceStore, Upload};
+use crate::torrent::Torrent;
+
+/// Maximum number of peers downloaded from at the same time
+const MAX_PEERS: usize = 8;
+
+/// Pieces still to download and peers still to try, shared by every peer session
+#[derive(Default)]
+struct State {
+    pending: BTreeSet<u32>,
+    in_progress: HashSet<u32>,
+    candidates: VecDeque<SocketAddr>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Woken whenever a piece is completed or put back in the queue
+    changed: Notify,
+}
+
+impl Shared {
+    fn next_candidate(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().candidates.pop_front()
+    }
+
+    /// Take the next pending piece owned by the peer
+    fn take_piece(&self, bitfield: &Bitfield) -> Next {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&index) = state.pending.iter().find(|&&index| bitfield.has(index)) {
+            state.pending.remove(&index);
+            state.in_progress.insert(index);
+            return Next::Download(index);
+        }
+        // Only wait if a piece in flight could come back to us
+        if state.in_progress.iter().any(|&index| bitfield.has(index)) {
+            Next::Wait
+        } else {
+            Next::Done
+        }
+    }
+
+    fn complete(&self, index: u32) {
+        self.state.lock().unwrap().in_progress.remove(&index);
+        self.changed.notify_waiters();
+    }
+
+    fn requeue(&self, index: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.in_progress.remove(&index);
+        state.pending.insert(index);
+        drop(state);
+        self.changed.notify_waiters();
+    }
+}
+
+/// What a peer session does next
+enum Next {
+    Download(u32),
+    /// Pieces the peer has are downloaded elsewhere and may come back
+    Wait,
+    /// The peer has nothing we still want
+    Done,
+}
+
+/// What every peer session of a download needs
+struct Swarm {
+    torrent: Arc<Torrent>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    /// Number of block requests kept in flight per peer
+    pipeline: usize,
+    /// Pieces we serve to the peers, `None` when we only download
+    store: Option<Arc<PieceStore>>,
+    /// Extensions advertised to every peer
+    extensions: Arc<ExtensionRegistry>,
+    shared: Shared,
+}
+
+impl Swarm {
+    fn upload(&self) -> Option<Upload> {
+        self.store.clone().map(Upload::new)
+    }
+}
+
+/// Distribute pieces among sessions to many peers at once
+pub struct Scheduler {
+    swarm: Arc<Swarm>,
+}
+
+impl Scheduler {
+    pub fn new(
+        torrent: Arc<Torrent>,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        pieces: impl IntoIterator<Item = u32>,
+        pipeline: usize,
+        store: Option<Arc<PieceStore>>,
+    ) -> Scheduler {
+        let shared = Shared::default();
+        shared.state.lock().unwrap().pending = pieces.into_iter().collect();
+        let extensions = Arc::new(ExtensionRegistry::new());
+        Scheduler { swarm: Arc::new(Swarm { torrent, info_hash, peer_id, pipeline, store, extensions, shared }) }
+    }
+
+    /// Number of pieces left to download
+    pub fn pending(&self) -> usize {
+        self.swarm.shared.state.lock().unwrap().pending.len()
+    }
+
+    /// Start downloading from the peers, and from the peers connecting to us if `inbound` is given.
+    /// Verified pieces are sent on the returned channel, which is closed once every piece is done
+    /// or no peer is left to download from.
+    pub fn start(self, peers: Vec<SocketAddr>, inbound: Option<mpsc::Receiver<InboundPeer>>) -> mpsc::Receiver<(u32, Vec<u8>)> {
+        let (tx, rx) = mpsc::channel(MAX_PEERS);
+        let slots = std::cmp::min(MAX_PEERS, peers.len());
+        self.swarm.shared.state.lock().unwrap().candidates = peers.into();
+
+        for _ in 0..slots {
+            let swarm = self.swarm.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                // When a peer fails, the slot moves on to the next candidate
+                while let Some(addr) = swarm.shared.next_candidate() {
+                    match run_peer(addr, &swarm, &tx).await {
+                        Ok(()) => {
+                            if swarm.shared.state.lock().unwrap().pending.is_empty() {
+                                break;
+                            }
+                        }
+                        Err(err) => println!("Peer {} failed: {:#}", addr, err),
+                    }
+                }
+            });
+        }
+
+        if let Some(inbound) = inbound {
+            // Inbound sessions only hold the channel while they run, so that it is still
+            // closed once no peer is left
+            let tx = tx.downgrade();
+            tokio::spawn(accept_inbound(inbound, tx, self.swarm));
+        }
+        rx
+    }
+}
+
+/// Run a session for every peer connecting to us, up to `MAX_PEERS` at once
+async fn accept_inbound(mut inbound: mpsc::Receiver<InboundPeer>, tx: mpsc::WeakSender<(u32, Vec<u8>)>, swarm: Arc<Swarm>) {
+    let slots = Arc::new(Semaphore::new(MAX_PEERS));
+    while let Some(inbound_peer) = inbound.recv().await {
+        let Some(tx) = tx.upgrade() else {
+            // The download is over
+            return;
+        };
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            println!("Too many inbound peers, dropping {}", inbound_peer.addr);
+            continue;
+        };
+        let swarm = swarm.clone();
+        tokio::spawn(async move {
+            let addr = inbound_peer.addr;
+            let result = match PeerSession::accept(inbound_peer, swarm.torrent.piece_count(), swarm.extensions.clone(), swarm.upload()).await {
+                Ok(peer) => run_connection(peer, &swarm, &tx).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                println!("Peer {} failed: {:#}", addr, err);
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// Download pieces from one peer until it has nothing left to offer us
+async fn run_peer(addr: SocketAddr, swarm: &Swarm, tx: &mpsc::Sender<(u32, Vec<u8>)>) -> Result<()> {
+    let peer = PeerSession::connect(addr, &swarm.info_hash, &swarm.peer_id, swarm.torrent.piece_count(), swarm.extensions.clone(), swarm.upload()).await?;
+    run_connection(peer, swarm, tx).await
+}
+
+/// Download pieces over an established connection until the peer has nothing left to offer us.
+/// The connection is then kept to serve the peer if we upload.
+async fn run_connection(mut peer: PeerSession, swarm: &Swarm, tx: &mpsc::Sender<(u32, Vec<u8>)>) -> Result<()> {
+    let addr = peer.addr;
+    let torrent = &swarm.torrent;
+    // Peers without pieces we want are let go before we wait for an unchoke
+    peer.wait_announced().await?;
+
+    while let Some(index) = next_piece(&mut peer, &swarm.shared).await? {
+        // The peer unchoking us is awaited while downloading the piece
+        peer.set_interested(true).await?;
+        match download_piece(&mut peer, torrent, index, swarm.pipeline).await {
+            Ok(data) if verify_piece(torrent, index, &data) => {
+                swarm.shared.complete(index);
+                if tx.send((index, data)).await.is_err() {
+                    // Nobody is waiting for pieces anymore
+                    break;
+                }
+            }
+            Ok(_) => {
+                println!("Hash mismatch for piece {} from peer {}", index, addr);
+                swarm.shared.requeue(index);
+                if peer.penalise() {
+                    bail!("dropped after {} corrupt pieces", peer.strikes);
+                }
+            }
+            Err(err) => {
+                swarm.shared.requeue(index);
+                return Err(err.context(format!("Error downloading piece {}", index)));
+            }
+        }
+    }
+
+    if peer.upload.is_some() {
+        // The download slot moves on while the connection keeps serving
+        peer.set_interested(false).await?;
+        tokio::spawn(async move {
+            if let Err(err) = peer.serve().await {
+                println!("Stopped serving peer {}: {:#}", addr, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Take the next piece to download from the peer. While pieces it has are downloaded elsewhere,
+/// its messages are still read so that its announces update the bitfield and it is still served.
+/// Returns `None` once the peer is of no more use.
+async fn next_piece(peer: &mut PeerSession, shared: &Shared) -> Result<Option<u32>> {
+    loop {
+        let notified = shared.changed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        match shared.take_piece(&peer.bitfield) {
+            Next::Download(index) => return Ok(Some(index)),
+            Next::Done => return Ok(None),
+            Next::Wait => {}
+        }
+        tokio::select! {
+            _ = notified => {}
+            message = peer.next_message() => {
+                message?;
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use crate::bitfield::Bitfield;
+use crate::extension::{self, ExtensionHandshake, ExtensionRegistry};
+use crate::frame::MessageDecoder;
+use crate::listener::InboundPeer;
+use crate::peers::{self, make_handshake, Handshake, PeerMessage};
+use crate::upload::Upload;
+
+/// Number of corrupt pieces a peer may send before we stop using it
+const MAX_STRIKES: u32 = 2;
+/// A keep-alive is sent when we have sent nothing else for this long
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// Peers sending nothing for this long, not even keep-alives, are considered gone
+const IDLE_TIMEOUT: Duration = KEEP_ALIVE_INTERVAL.saturating_mul(2);
+/// Peers that neither unchoke us nor send blocks for this long are given up on
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time a peer has to tell which pieces it has, peers without any may say nothing at all
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A handshaken connection to a peer and the state of both ends. Messages may arrive
+/// in any order, every one of them updates the state before the next is read.
+pub struct PeerSession {
+    pub addr: SocketAddr,
+    framed: Framed<TcpStream, MessageDecoder>,
+    /// Messages accepted for sending and not handed to the connection yet
+    outbox: VecDeque<PeerMessage>,
+    /// Whether we refuse to serve the peer's requests
+    pub am_choking: bool,
+    /// Whether we told the peer we want pieces it has
+    pub am_interested: bool,
+    /// Whether the peer refuses to serve our requests
+    pub peer_choking: bool,
+    /// Whether the peer wants pieces we have
+    pub peer_interested: bool,
+    /// Pieces the peer has announced
+    pub bitfield: Bitfield,
+    piece_count: u32,
+    /// Whether both ends support the Fast Extension (BEP 6)
+    pub fast: bool,
+    /// Pieces the peer lets us download while it chokes us
+    pub allowed_fast: HashSet<u32>,
+    /// Block requests sent and not answered yet, keyed by (index, begin), with their length
+    pub requests: HashMap<(u32, u32), u32>,
+    /// When the peer last sent us a block
+    pub last_block: Instant,
+    /// When we last sent a message
+    last_sent: Instant,
+    /// When the peer last sent a message
+    last_received: Instant,
+    /// Number of pieces received from this peer that failed verification
+    pub strikes: u32,
+    /// Serves the peer's requests from our pieces, `None` when we only download
+    pub upload: Option<Upload>,
+    /// Extensions we advertise, which give the ids of the extended messages we receive
+    extensions: Arc<ExtensionRegistry>,
+    /// Extension handshake of the peer, once received: the ids to send extended messages with
+    pub peer_extensions: Option<ExtensionHandshake>,
+}
+
+impl PeerSession {
+    pub async fn connect(addr: SocketAddr, info_hash: &[u8; 20], peer_id: &[u8; 20], piece_count: u32, extensions: Arc<ExtensionRegistry>, upload: Option<Upload>) -> Result<PeerSession> {
+        let mut stream = peers::connect(addr).await?;
+        let (handshake, buffered) = make_handshake(&mut stream, info_hash, peer_id).await.context("Error making handshake")?;
+        let framed = MessageDecoder::framed(stream, buffered);
+        PeerSession::establish(addr, framed, &handshake, piece_count, extensions, upload).await
+    }
+
+    /// Take over a peer that connected to us, its handshake already answered
+    pub async fn accept(peer: InboundPeer, piece_count: u32, extensions: Arc<ExtensionRegistry>, upload: Option<Upload>) -> Result<PeerSession> {
+        let framed = MessageDecoder::framed(peer.stream, peer.buffered);
+        PeerSession::establish(peer.addr, framed, &peer.handshake, piece_count, extensions, upload).await
+    }
+
+    /// Set up a session once handshakes have been exchanged, whoever initiated it
+    async fn establish(addr: SocketAddr, framed: Framed<TcpStream, MessageDecoder>, handshake: &Handshake, piece_count: u32, extensions: Arc<ExtensionRegistry>, upload: Option<Upload>) -> Result<PeerSession> {
+        // Both ends start out choking and not interested
+        let mut session = PeerSession {
+            addr,
+            framed,
+            outbox: VecDeque::new(),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: Bitfield::with_pieces(piece_count as usize),
+            piece_count,
+            fast: handshake.supports_fast(),
+            allowed_fast: HashSet::new(),
+            requests: HashMap::new(),
+            last_block: Instant::now(),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            strikes: 0,
+            upload,
+            extensions,
+            peer_extensions: None,
+        };
+
+        if handshake.supports_extensions() {
+            let extensions = session.extensions.handshake();
+            session.send(extension::extended_message(extension::HANDSHAKE_ID, &extensions.to_bytes()?)).await
+                .context("Error sending extension handshake")?;
+        }
+
+        let allowed_fast = match &mut session.upload {
+            Some(upload) if session.fast => upload.enable_fast(addr.ip(), &handshake.info_hash),
+            _ => Vec::new(),
+        };
+        let bitfield = match &session.upload {
+            Some(upload) => upload.bitfield_message(),
+            // With the Fast Extension the peer must always be told what we have
+            None if session.fast => Some(PeerMessage::HaveNone),
+            None => None,
+        };
+        if let Some(bitfield) = bitfield {
+            session.send(bitfield).await.context("Error sending bitfield")?;
+        }
+        for message in allowed_fast {
+            session.send(message).await.context("Error sending allowed fast message")?;
+        }
+        Ok(session)
+    }
+
+    /// Send a message, keeping track of what it tells the peer
+    pub async fn send(&mut self, message: PeerMessage) -> Result<()> {
+        self.queue(message);
+        self.flush().await
+    }
+
+    /// Queue a message for sending, what it tells the peer is recorded right away
+    fn queue(&mut self, message: PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            PeerMessage::Request { index, begin, length } => {
+                self.requests.insert((index, begin), length);
+            }
+            PeerMessage::Cancel { index, begin, .. } => {
+                self.requests.remove(&(index, begin));
+            }
+            _ => {}
+        }
+        self.outbox.push_back(message);
+    }
+
+    /// Send the queued messages. Cancelling is safe: a message only leaves the queue once
+    /// the connection took it, the rest is sent by the next call.
+    async fn flush(&mut self) -> Result<()> {
+        while !self.outbox.is_empty() {
+            std::future::poll_fn(|cx| self.framed.poll_ready_unpin(cx)).await?;
+            if let Some(message) = self.outbox.pop_front() {
+                self.framed.start_send_unpin(message)?;
+                self.last_sent = Instant::now();
+            }
+        }
+        self.framed.flush().await?;
+        Ok(())
+    }
+
+    /// Whether we may request blocks of the piece at `index` right now
+    pub fn can_request(&self, index: u32) -> bool {
+        !self.peer_choking || (self.fast && self.allowed_fast.contains(&index))
+    }
+
+    /// Wait for the peer to tell which pieces it has. Only its first message may be a bitfield,
+    /// `HaveAll` or `HaveNone`, so any message ends the wait, as does a silent peer.
+    pub async fn wait_announced(&mut self) -> Result<()> {
+        let announced = async {
+            // The extension handshake may come first
+            while let PeerMessage::Extended { .. } = self.next_message().await? {}
+            Ok(())
+        };
+        tokio::time::timeout(ANNOUNCE_TIMEOUT, announced).await.unwrap_or(Ok(()))
+    }
+
+    /// Tell the peer whether we want pieces it has, if that changed
+    pub async fn set_interested(&mut self, interested: bool) -> Result<()> {
+        if interested != self.am_interested {
+            let message = if interested { PeerMessage::Interested } else { PeerMessage::NotInterested };
+            self.send(message).await.context("Error sending interest")?;
+        }
+        Ok(())
+    }
+
+    /// Serve the peer's requests until it closes the connection or has every piece
+    pub async fn serve(mut self) -> Result<()> {
+        while !(0..self.piece_count).all(|index| self.bitfield.has(index)) {
+            self.next_message().await?;
+        }
+        Ok(())
+    }
+
+    /// Name of the extension an extended message from the peer with this id is meant for
+    pub fn extension_name(&self, id: u8) -> Option<&'static str> {
+        self.extensions.name_of(id)
+    }
+
+    /// Id to send the extension `name` to the peer with, `None` if it does not support it
+    pub fn peer_extension_id(&self, name: &str) -> Option<u8> {
+        self.peer_extensions.as_ref()?.id_of(name)
+    }
+
+    /// Record a corrupt piece, returns true when the peer should be dropped
+    pub fn penalise(&mut self) -> bool {
+        self.strikes += 1;
+        self.strikes >= MAX_STRIKES
+    }
+
+    /// Read the next message from the peer and update the session with it. Its requests are
+    /// absorbed and answered along the way. Our own `Have`, `Choke` and `Unchoke` messages and
+    /// keep-alives are sent while waiting. Cancelling is safe, nothing queued is lost.
+    pub async fn next_message(&mut self) -> Result<PeerMessage> {
+        loop {
+            self.flush().await.context("Error sending message")?;
+            let keep_alive = tokio::time::sleep_until(self.last_sent + KEEP_ALIVE_INTERVAL);
+            let idle = tokio::time::sleep_until(self.last_received + IDLE_TIMEOUT);
+            let message = match &mut self.upload {
+                Some(upload) => tokio::select! {
+                    message = self.framed.next() => message,
+                    _ = idle => bail!("Peer sent nothing for {} seconds", IDLE_TIMEOUT.as_secs()),
+                    outgoing = upload.next_message(self.am_choking) => {
+                        self.queue(outgoing);
+                        continue;
+                    }
+                    _ = keep_alive => {
+                        self.queue(PeerMessage::KeepAlive);
+                        continue;
+                    }
+                },
+                None => tokio::select! {
+                    message = self.framed.next() => message,
+                    _ = idle => bail!("Peer sent nothing for {} seconds", IDLE_TIMEOUT.as_secs()),
+                    _ = keep_alive => {
+                        self.queue(PeerMessage::KeepAlive);
+                        continue;
+                    }
+                },
+            };
+            let message = message.context("Peer closed the connection")??;
+            self.last_received = Instant::now();
+            // The connection must be closed when the Fast Extension was not negotiated (BEP 6)
+            if !self.fast && matches!(message, PeerMessage::SuggestPiece(_) | PeerMessage::HaveAll | PeerMessage::HaveNone
+                | PeerMessage::RejectRequest { .. } | PeerMessage::AllowedFast(_)) {
+                bail!("Peer sent {:?} without supporting the Fast Extension", message);
+            }
+
+            match message {
+                // Pieces the torrent does not have mean a broken or malicious peer (BEP 3)
+                PeerMessage::Bitfield(ref bitfield) => {
+                    if !bitfield.fits(self.piece_count as usize) {
+                        bail!("Peer sent a bitfield of {} bytes not fitting {} pieces", bitfield.as_bytes().len(), self.piece_count);
+                    }
+                    self.bitfield = bitfield.clone();
+                }
+                PeerMessage::Have(index) => {
+                    if index >= self.piece_count {
+                        bail!("Peer announced piece {}, the torrent has {} pieces", index, self.piece_count);
+                    }
+                    self.bitfield.set(index);
+                }
+                PeerMessage::HaveAll => self.bitfield = Bitfield::full(self.piece_count as usize),
+                PeerMessage::HaveNone => self.bitfield = Bitfield::with_pieces(self.piece_count as usize),
+                PeerMessage::AllowedFast(index) => {
+                    self.allowed_fast.insert(index);
+                }
+                PeerMessage::Choke => {
+                    self.peer_choking = true;
+                    // Without the Fast Extension a choke drops every pending request,
+                    // with it the peer rejects the ones it will not serve
+                    if !self.fast {
+                        self.requests.clear();
+                    }
+                }
+                PeerMessage::Unchoke => self.peer_choking = false,
+                PeerMessage::Interested => self.peer_interested = true,
+                PeerMessage::NotInterested => self.peer_interested = false,
+                PeerMessage::Piece { index, begin, .. } => {
+                    self.requests.remove(&(index, begin));
+                    self.last_block = Instant::now();
+                }
+                // A later extension handshake updates the earlier one, which is simply replaced
+                PeerMessage::Extended { id: extension::HANDSHAKE_ID, ref payload } => {
+                    self.peer_extensions = Some(ExtensionHandshake::from_bytes(payload)?);
+                }
+                PeerMessage::RejectRequest { index, begin, .. } => {
+                    self.requests.remove(&(index, begin));
+                    // A choking peer rejecting an allowed fast piece no longer lets us have it
+                    if self.peer_choking {
+                        self.allowed_fast.remove(&index);
+                    }
+                }
+                _ => {}
+            }
+            if let Some(upload) = &mut self.upload {
+                if let Some(replies) = upload.handle(&message, self.am_choking)? {
+                    for reply in replies {
+                        self.queue(reply);
+                    }
+                    continue;
+                }
+            }
+            return Ok(message);
+        }
+    }
+}
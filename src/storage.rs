@@ -0,0 +1,178 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use crate::torrent::{Keys, Torrent};
+
+/// A file of the torrent and where its bytes start in the torrent data
+#[derive(Debug)]
+struct FileEntry {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Maps pieces onto the files of a torrent on disk
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+}
+
+impl Storage {
+    /// Create every file of the torrent with its final size. A single-file torrent is
+    /// written to `output`, a multi-file torrent under the `output/<name>` directory.
+    pub fn new(torrent: &Torrent, output: &Path) -> Result<Storage> {
+        let storage = Storage::layout(torrent, output)?;
+        for file in &storage.files {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent).with_context(|| format!("Error creating directory {}", parent.display()))?;
+            }
+            let handle = OpenOptions::new().create(true).truncate(false).write(true).open(&file.path)
+                .with_context(|| format!("Error creating file {}", file.path.display()))?;
+            handle.set_len(file.length)?;
+        }
+        Ok(storage)
+    }
+
+    /// Use files already on disk, laid out as `new` would create them
+    pub fn open(torrent: &Torrent, path: &Path) -> Result<Storage> {
+        let storage = Storage::layout(torrent, path)?;
+        for file in &storage.files {
+            let length = fs::metadata(&file.path)
+                .with_context(|| format!("Error opening file {}", file.path.display()))?
+                .len();
+            if length != file.length {
+                bail!("File {} has {} bytes, expected {}", file.path.display(), length, file.length);
+            }
+        }
+        Ok(storage)
+    }
+
+    fn layout(torrent: &Torrent, output: &Path) -> Result<Storage> {
+        let mut files = Vec::new();
+        match &torrent.info.keys {
+            Keys::SingleFile { length } => {
+                files.push(FileEntry { path: output.to_path_buf(), offset: 0, length: *length });
+            }
+            Keys::MultiFile { files: torrent_files } => {
+                let mut root = output.to_path_buf();
+                push_component(&mut root, &torrent.info.name)?;
+                let mut offset = 0;
+                for file in torrent_files {
+                    let mut path = root.clone();
+                    for component in &file.path {
+                        push_component(&mut path, component)?;
+                    }
+                    files.push(FileEntry { path, offset, length: file.length });
+                    offset = offset.checked_add(file.length).context("Torrent files are too large")?;
+                }
+            }
+        }
+
+        Ok(Storage { files, piece_length: torrent.info.piece_length })
+    }
+
+    /// Write a verified piece to the files it spans
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        let start = index as u64 * self.piece_length;
+        let end = start + data.len() as u64;
+
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            // Part of the piece that belongs to this file
+            let from = std::cmp::max(start, file.offset);
+            let to = std::cmp::min(end, file_end);
+
+            let mut handle = OpenOptions::new().write(true).open(&file.path)
+                .with_context(|| format!("Error opening file {}", file.path.display()))?;
+            handle.seek(SeekFrom::Start(from - file.offset))?;
+            handle.write_all(&data[(from - start) as usize..(to - start) as usize])
+                .with_context(|| format!("Error writing piece {} to {}", index, file.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes of the piece at `index`, starting at `begin`, from the files they span
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let start = index as u64 * self.piece_length + begin as u64;
+        let end = start + length as u64;
+        let mut data = vec![0; length as usize];
+
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            let from = std::cmp::max(start, file.offset);
+            let to = std::cmp::min(end, file_end);
+
+            let mut handle = fs::File::open(&file.path)
+                .with_context(|| format!("Error opening file {}", file.path.display()))?;
+            handle.seek(SeekFrom::Start(from - file.offset))?;
+            handle.read_exact(&mut data[(from - start) as usize..(to - start) as usize])
+                .with_context(|| format!("Error reading piece {} from {}", index, file.path.display()))?;
+        }
+        Ok(data)
+    }
+}
+
+/// Add a name from the torrent to `path`. Never let a torrent write outside of its directory.
+fn push_component(path: &mut PathBuf, component: &str) -> Result<()> {
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => path.push(component),
+        _ => bail!("Invalid path component {:?} in torrent", component),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::torrent::File;
+    use super::*;
+
+    fn multi_file(name: &str, piece_length: u64, files: &[(&str, u64)]) -> Torrent {
+        let mut torrent = Torrent::new();
+        torrent.info.name = name.to_string();
+        torrent.info.piece_length = piece_length;
+        torrent.info.keys = Keys::MultiFile {
+            files: files.iter().map(|&(path, length)| File { length, path: vec![path.to_string()] }).collect(),
+        };
+        torrent
+    }
+
+    #[test]
+    fn name_stays_inside_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("inner");
+        for name in ["/tmp/escape", "..", "a/b", ""] {
+            assert!(Storage::new(&multi_file(name, 16, &[("x", 1)]), &output).is_err(), "{:?} accepted", name);
+        }
+        assert!(!dir.path().join("x").exists());
+    }
+
+    #[test]
+    fn pieces_span_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // Piece 0 spans a and b, the last piece spans b and c, which is shorter than a piece
+        let torrent = multi_file("dir", 8, &[("a", 5), ("b", 6), ("c", 3)]);
+        let storage = Storage::new(&torrent, dir.path()).unwrap();
+        storage.write_piece(1, b"89abcd").unwrap();
+        storage.write_piece(0, b"01234567").unwrap();
+
+        let root = dir.path().join("dir");
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"01234");
+        assert_eq!(fs::read(root.join("b")).unwrap(), b"56789a");
+        assert_eq!(fs::read(root.join("c")).unwrap(), b"bcd");
+
+        assert_eq!(storage.read_block(0, 3, 5).unwrap(), b"34567");
+        assert_eq!(storage.read_block(1, 1, 5).unwrap(), b"9abcd");
+        // Files on disk are found again by `open`
+        let reopened = Storage::open(&torrent, dir.path()).unwrap();
+        assert_eq!(reopened.read_block(1, 0, 6).unwrap(), b"89abcd");
+    }
+}
@@ -1,33 +1,269 @@
-use serde_bytes::ByteBuf;
+use anyhow::{Context, Result};
+use serde::{Deserialize as _, Deserializer};
+use serde::de::Error as _;
 use serde_derive::{Deserialize, Serialize};
+use crate::BLOCK_SIZE;
+use crate::decode::Parser;
+use sha1::{Digest, Sha1};
+use crate::value::BencodeValue;
+use crate::torrent::hashes::Hashes;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Torrent
 {
     pub info: Info,
+    /// Ignored by clients supporting `announce-list` when that key is present
+    #[serde(default)]
     pub announce: String,
+    /// Tiers of tracker URLs (BEP 12), tried in order
+    #[serde(rename = "announce-list", default, skip_serializing_if = "Vec::is_empty")]
+    pub announce_list: Vec<Vec<String>>,
+}
+
+impl Default for Torrent {
+    fn default() -> Self {
+        Torrent::new()
+    }
 }
 
 impl Torrent {
 
+    /// Load a torrent from the content of a .torrent file
+    pub fn from_bytes(content: &[u8]) -> Result<Torrent> {
+        let torrent: Torrent = crate::de::from_bytes(content).context("Invalid torrent file")?;
+        torrent.info.check().context("Invalid torrent file")?;
+        Ok(torrent)
+    }
+
     pub fn new() -> Torrent {
         Torrent {
             info: Info {
                 name: "".to_string(),
-                pieces: ByteBuf::new(),
+                pieces: Hashes(Vec::new()),
                 piece_length: 0,
-                length: 0,
+                keys: Keys::SingleFile { length: 0 },
             },
             announce: "".to_string(),
+            announce_list: Vec::new(),
+        }
+    }
+
+    /// Tracker tiers to announce to: `announce-list` if present, `announce` otherwise
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self.announce_list.iter()
+            .map(|tier| tier.iter().filter(|url| !url.is_empty()).cloned().collect())
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
+        if !tiers.is_empty() {
+            return tiers;
+        }
+        if self.announce.is_empty() {
+            Vec::new()
+        } else {
+            vec![vec![self.announce.clone()]]
         }
     }
+
+    /// Expected SHA-1 hash of the piece at `index`
+    pub fn piece_hash(&self, index: u32) -> Option<&[u8; 20]> {
+        self.info.pieces.0.get(index as usize)
+    }
+
+    /// Total number of bytes of the torrent
+    pub fn length(&self) -> u64 {
+        self.info.total_length()
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.info.pieces.0.len() as u32
+    }
+
+    /// Size of the piece at `index`: the last piece holds whatever is left of the data
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let piece_length = self.info.piece_length;
+        let start = index as u64 * piece_length;
+        std::cmp::min(piece_length, self.length().saturating_sub(start)) as u32
+    }
+
+    /// Number of `BLOCK_SIZE` blocks needed to request the piece at `index`
+    pub fn block_count(&self, index: u32) -> u32 {
+        self.piece_size(index).div_ceil(BLOCK_SIZE)
+    }
+
+    /// Size of a block of a piece: the last block holds whatever is left of the piece
+    pub fn block_size(&self, index: u32, block: u32) -> u32 {
+        let begin = block * BLOCK_SIZE;
+        std::cmp::min(BLOCK_SIZE, self.piece_size(index).saturating_sub(begin))
+    }
 }
 
 
+/// SHA-1 hash of the `info` dictionary, taken over its bytes in the .torrent file
+pub fn info_hash(content: &[u8]) -> Result<[u8; 20]> {
+    let mut parser = Parser::new(content);
+    let BencodeValue::BDictionary(_) = parser.parse()? else {
+        anyhow::bail!("Torrent file is not a dictionary");
+    };
+    let info = parser.span_of(1, b"info").context("Missing info dictionary")?;
+    Ok(Sha1::digest(&content[info]).into())
+}
+
+/// Largest piece length accepted, whole pieces are held in memory
+const MAX_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// Piece lengths must be positive, and small enough to hold a piece in memory
+fn piece_length<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(D::Error::custom("piece length must be positive")),
+        length if length > MAX_PIECE_LENGTH => Err(D::Error::custom(format!("piece length {} is too large", length))),
+        length => Ok(length),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Info {
     pub name: String,
-    pub pieces: ByteBuf,
-    pub piece_length: i64,
-    pub length: i64,
-}
\ No newline at end of file
+    pub pieces: Hashes,
+    #[serde(rename = "piece length", deserialize_with = "piece_length")]
+    pub piece_length: u64,
+    #[serde(flatten)]
+    pub keys: Keys,
+}
+
+impl Info {
+    /// Total number of bytes of the torrent, adding up every file in multi-file mode
+    pub fn total_length(&self) -> u64 {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().fold(0, |total: u64, file| total.saturating_add(file.length)),
+        }
+    }
+
+    /// Make sure there is one piece hash for every `piece_length` bytes of the torrent
+    pub fn check(&self) -> Result<()> {
+        let expected = self.total_length().div_ceil(self.piece_length);
+        if self.pieces.0.len() as u64 != expected {
+            anyhow::bail!("{} piece hashes for {} bytes in pieces of {} bytes, expected {}",
+                self.pieces.0.len(), self.total_length(), self.piece_length, expected);
+        }
+        Ok(())
+    }
+}
+
+/// A torrent either describes a single file (`length`) or a directory of files (`files`)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged, expecting = "a `length` key for a single file or a `files` list")]
+pub enum Keys {
+    SingleFile {
+        length: u64,
+    },
+    MultiFile {
+        files: Vec<File>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct File {
+    pub length: u64,
+    /// Path components relative to the torrent directory, the last one being the file name
+    pub path: Vec<String>,
+}
+
+pub mod hashes {
+    use std::fmt;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Visitor;
+
+    /// The `pieces` field: the concatenated 20-byte SHA-1 hashes of every piece
+    #[derive(Debug, Clone)]
+    pub struct Hashes(pub Vec<[u8; 20]>);
+
+    impl Hashes {
+        pub fn from_bytes(v: &[u8]) -> Option<Hashes> {
+            if !v.len().is_multiple_of(20) {
+                return None;
+            }
+            Some(Hashes(
+                v.chunks_exact(20)
+                    .map(|slice_20| slice_20.try_into().expect("chunk is 20 bytes long"))
+                    .collect(),
+            ))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Hashes {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(HashesVisitor)
+        }
+    }
+
+    impl Serialize for Hashes {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+        {
+            serializer.serialize_bytes(&self.0.concat())
+        }
+    }
+
+    struct HashesVisitor;
+
+    impl<'de> Visitor<'de> for HashesVisitor {
+        type Value = Hashes;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a byte string whose length is a multiple of 20")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+        {
+            Hashes::from_bytes(v).ok_or_else(|| E::custom(format!("bytes length error for pieces {}", v.len())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent(piece_length: &str, length: &str) -> Result<Torrent> {
+        let content = format!("d8:announce3:url4:infod6:lengthi{}e4:name1:a12:piece lengthi{}e6:pieces20:aaaaaaaaaaaaaaaaaaaaee", length, piece_length);
+        Torrent::from_bytes(content.as_bytes())
+    }
+
+    #[test]
+    fn valid_lengths() {
+        let torrent = torrent("16384", "1000").unwrap();
+        assert_eq!(torrent.info.piece_length, 16384);
+        assert_eq!(torrent.piece_size(0), 1000);
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        assert!(torrent("0", "1000").is_err());
+        assert!(torrent("-16384", "1000").is_err());
+        assert!(torrent("16384", "-1").is_err());
+        assert!(torrent("1099511627776", "1000").is_err());
+    }
+
+    #[test]
+    fn info_hash_of_raw_bytes() {
+        // Not canonical: a negative zero and keys out of order, re-encoding would change the hash
+        let info = b"d4:name1:a6:lengthi1e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa1:xi-0ee";
+        let content = [b"d8:announce3:url4:info".as_slice(), info, b"e"].concat();
+        assert_eq!(info_hash(&content).unwrap(), <[u8; 20]>::from(Sha1::digest(info)));
+    }
+
+    #[test]
+    fn piece_count_matches_length() {
+        assert_eq!(torrent("16384", "16384").unwrap().piece_count(), 1);
+        // Too few hashes, then too many
+        assert!(torrent("16384", "16385").is_err());
+        assert!(torrent("16384", "0").is_err());
+    }
+}
@@ -1,36 +1,385 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
-use crate::peers::addr::Address;
+use crate::error::TrackerError;
+use crate::peers::addr::{Address, Address6};
+use crate::random;
+use crate::udp_tracker::{UdpAnnounceRequest, UdpEvent, UdpTracker};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Info hashes sent in one scrape request, as many as fit in a UDP packet
+const MAX_SCRAPE_HASHES: usize = 74;
+/// Give up on an unresponsive tracker after this long so the next one can be tried
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Retransmissions to a UDP tracker before trying the next one, about two minutes
+const UDP_MAX_RETRIES: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackerRequest {
-    pub peer_id: String,
+    /// Raw bytes, percent-encoded by hand like the info hash
+    #[serde(skip)]
+    pub peer_id: [u8; 20],
     pub port: u16,
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<TrackerEvent>,
+    /// Tracker id received in a previous response of the same tracker
+    #[serde(rename = "trackerid", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
 }
 
 // Implement default for TrackerRequest
 impl Default for TrackerRequest {
     fn default() -> Self {
         TrackerRequest {
-            peer_id: "".to_string(),
+            peer_id: [0; 20],
             port: 0,
             uploaded: 0,
             downloaded: 0,
             left: 0,
             compact: 1,
+            event: None,
+            tracker_id: None,
         }
     }
 }
 
+/// Lifecycle events reported to trackers, regular announces have none
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl From<Option<TrackerEvent>> for UdpEvent {
+    fn from(event: Option<TrackerEvent>) -> Self {
+        match event {
+            None => UdpEvent::None,
+            Some(TrackerEvent::Started) => UdpEvent::Started,
+            Some(TrackerEvent::Completed) => UdpEvent::Completed,
+            Some(TrackerEvent::Stopped) => UdpEvent::Stopped,
+        }
+    }
+}
+
+/// Bencoded answer of an HTTP tracker, every key being optional
 #[derive(Debug, Deserialize)]
-pub struct TrackerResponseSuccess {
-    pub interval: i64,
+pub struct TrackerResponse {
+    /// When present, no other key is meaningful
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
+    pub interval: Option<i64>,
     #[serde(rename = "min interval")]
-    pub min_interval: i64,
-    pub incomplete: i64,
+    pub min_interval: Option<i64>,
+    /// To be sent back on the next announces
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<String>,
+    pub complete: Option<i64>,
+    pub incomplete: Option<i64>,
+    pub peers: Option<Address>,
+    /// IPv6 peers (BEP 7)
+    pub peers6: Option<Address6>,
+}
+
+/// Result of a successful announce to an HTTP or UDP tracker
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceResponse {
+    pub peers: Vec<SocketAddr>,
+    /// Seconds to wait before announcing again
+    pub interval: Option<u64>,
+    pub min_interval: Option<u64>,
+    pub tracker_id: Option<String>,
+    /// Number of seeders
+    pub complete: Option<u64>,
+    /// Number of leechers
+    pub incomplete: Option<u64>,
+    /// The request succeeded but the tracker has something to say about it
+    pub warning: Option<String>,
+}
+
+impl TryFrom<TrackerResponse> for AnnounceResponse {
+    type Error = TrackerError;
+
+    fn try_from(response: TrackerResponse) -> Result<Self, Self::Error> {
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        if response.peers.is_none() && response.peers6.is_none() {
+            return Err(TrackerError::MissingPeers);
+        }
+        let mut peers = response.peers.map(|Address(peers)| peers).unwrap_or_default();
+        for peer in response.peers6.map(|Address6(peers)| peers).unwrap_or_default() {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        // Negative numbers make no sense, treat them as missing
+        let count = |value: Option<i64>| value.and_then(|value| u64::try_from(value).ok());
+        Ok(AnnounceResponse {
+            peers,
+            interval: count(response.interval),
+            min_interval: count(response.min_interval),
+            tracker_id: response.tracker_id,
+            complete: count(response.complete),
+            incomplete: count(response.incomplete),
+            warning: response.warning_message,
+        })
+    }
+}
+
+/// Ask the tracker at `url` for peers, over HTTP or UDP depending on the URL scheme
+pub async fn announce(url: &str, info_hash: &[u8; 20], request: TrackerRequest) -> Result<AnnounceResponse> {
+    if url.starts_with("udp://") {
+        let mut tracker = UdpTracker::connect(url).await?;
+        tracker.max_retries = UDP_MAX_RETRIES;
+        let response = tracker.announce(&UdpAnnounceRequest {
+            info_hash: *info_hash,
+            peer_id: request.peer_id,
+            downloaded: request.downloaded,
+            left: request.left,
+            uploaded: request.uploaded,
+            event: request.event.into(),
+            port: request.port,
+        }).await?;
+        return Ok(AnnounceResponse {
+            peers: response.peers,
+            interval: Some(response.interval as u64),
+            complete: Some(response.seeders as u64),
+            incomplete: Some(response.leechers as u64),
+            ..AnnounceResponse::default()
+        });
+    }
+    http_announce(url, info_hash, request).await
+}
+
+/// Bencoded answer of an HTTP tracker to a scrape
+#[derive(Debug, Deserialize)]
+pub struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    /// Statistics keyed by the raw 20 bytes info hash
+    #[serde(default)]
+    pub files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ScrapeFile {
+    #[serde(default)]
     pub complete: i64,
-    pub peers: Address,
+    #[serde(default)]
+    pub incomplete: i64,
+    #[serde(default)]
+    pub downloaded: i64,
+}
+
+/// Swarm statistics of one torrent
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrapeStats {
+    pub info_hash: [u8; 20],
+    /// Number of seeders
+    pub complete: u64,
+    /// Number of leechers
+    pub incomplete: u64,
+    /// Number of completed downloads
+    pub downloaded: u64,
+}
+
+/// Scrape URL of an HTTP tracker: the last `announce` of the path replaced with `scrape`
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = announce.split_once('?').map_or((announce, None), |(path, query)| (path, Some(query)));
+    let slash = path.rfind('/')?;
+    let rest = path[slash + 1..].strip_prefix("announce")?;
+    let url = format!("{}scrape{}", &path[..=slash], rest);
+    Some(match query {
+        Some(query) => format!("{}?{}", url, query),
+        None => url,
+    })
+}
+
+/// Append `query` to a tracker URL, which may already carry one, such as a passkey
+fn with_query(url: &str, query: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query)
+}
+
+/// Ask the tracker at `url` for the statistics of several torrents, over HTTP or UDP.
+/// Torrents unknown to an HTTP tracker are left out of the result.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+    let mut stats = Vec::with_capacity(info_hashes.len());
+    if url.starts_with("udp://") {
+        let mut tracker = UdpTracker::connect(url).await?;
+        tracker.max_retries = UDP_MAX_RETRIES;
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let scrapes = tracker.scrape(chunk).await?;
+            stats.extend(chunk.iter().zip(scrapes).map(|(info_hash, scrape)| ScrapeStats {
+                info_hash: *info_hash,
+                complete: scrape.seeders as u64,
+                incomplete: scrape.leechers as u64,
+                downloaded: scrape.completed as u64,
+            }));
+        }
+        return Ok(stats);
+    }
+
+    let scrape_url = scrape_url(url).ok_or_else(|| TrackerError::NoScrape(url.to_string()))?;
+    let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+    for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+        let query = chunk.iter()
+            .map(|info_hash| format!("info_hash={}", url_encode(info_hash)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let response = client.get(with_query(&scrape_url, &query)).send().await?;
+        let status = response.status();
+        let response_bytes = response.bytes().await?;
+        let mut response: ScrapeResponse = match crate::de::from_bytes(&response_bytes) {
+            Ok(response) => response,
+            Err(_) if !status.is_success() => return Err(TrackerError::Status(status).into()),
+            Err(err) => return Err(TrackerError::InvalidResponse(err).into()),
+        };
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerError::Failure(reason).into());
+        }
+        for info_hash in chunk {
+            if let Some(file) = response.files.remove(serde_bytes::Bytes::new(info_hash)) {
+                let count = |value: i64| u64::try_from(value).unwrap_or_default();
+                stats.push(ScrapeStats {
+                    info_hash: *info_hash,
+                    complete: count(file.complete),
+                    incomplete: count(file.incomplete),
+                    downloaded: count(file.downloaded),
+                });
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Trackers of a torrent grouped in tiers (BEP 12)
+#[derive(Debug, Clone)]
+pub struct TrackerManager {
+    tiers: Vec<Vec<String>>,
+    /// Tracker ids received from each tracker URL
+    tracker_ids: HashMap<String, String>,
+}
+
+impl TrackerManager {
+    /// Trackers within a tier are shuffled once, tiers keep their order
+    pub fn new(mut tiers: Vec<Vec<String>>) -> TrackerManager {
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in &mut tiers {
+            random::shuffle(tier);
+        }
+        TrackerManager { tiers, tracker_ids: HashMap::new() }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Announce to the first tracker answering, trying each tier in order.
+    /// The tracker that answered is moved to the front of its tier.
+    pub async fn announce(&mut self, info_hash: &[u8; 20], request: TrackerRequest) -> Result<AnnounceResponse> {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for index in 0..tier.len() {
+                let request = TrackerRequest {
+                    tracker_id: self.tracker_ids.get(&tier[index]).cloned(),
+                    ..request.clone()
+                };
+                match announce(&tier[index], info_hash, request).await {
+                    Ok(response) => {
+                        let url = tier.remove(index);
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), tracker_id.clone());
+                        }
+                        tier.insert(0, url);
+                        return Ok(response);
+                    }
+                    Err(err) => {
+                        println!("Error announcing to {}: {:#}", tier[index], err);
+                        last_error = Some(err);
+                    }
+                }
+            }
+        }
+        // The error of the last tracker tried is kept so that callers can inspect it
+        match last_error {
+            Some(err) => Err(err.context("No tracker answered")),
+            None => bail!("Torrent has no tracker"),
+        }
+    }
+
+    /// Scrape the first tracker answering, trying each tier in order like `announce`.
+    /// Returns the URL of that tracker along with the statistics it sent.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<(String, Vec<ScrapeStats>)> {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for index in 0..tier.len() {
+                match scrape(&tier[index], info_hashes).await {
+                    Ok(stats) => {
+                        let url = tier.remove(index);
+                        tier.insert(0, url.clone());
+                        return Ok((url, stats));
+                    }
+                    Err(err) => {
+                        println!("Error scraping {}: {:#}", tier[index], err);
+                        last_error = Some(err);
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(err) => Err(err.context("No tracker answered the scrape")),
+            None => bail!("Torrent has no tracker"),
+        }
+    }
+}
+
+async fn http_announce(url: &str, info_hash: &[u8; 20], request: TrackerRequest) -> Result<AnnounceResponse> {
+    // This cannot be urlencoded by serialize, it goes apart
+    let encoded_info_hash = url_encode(info_hash);
+    let encoded_peer_id = url_encode(&request.peer_id);
+    let encoded_request = serde_urlencoded::to_string(request).context("Error encoding tracker request")?;
+
+    // Make request to tracker url
+    let url = with_query(url, &format!("{}&info_hash={}&peer_id={}", encoded_request, encoded_info_hash, encoded_peer_id));
+    let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?.get(url);
+    let response = client.send().await?;
+    let status = response.status();
+    let response_bytes = response.bytes().await?;
+    // Trackers may send a failure reason along with an error status
+    let response: TrackerResponse = match crate::de::from_bytes(&response_bytes) {
+        Ok(response) => response,
+        Err(_) if !status.is_success() => return Err(TrackerError::Status(status).into()),
+        Err(err) => return Err(TrackerError::InvalidResponse(err).into()),
+    };
+    Ok(AnnounceResponse::try_from(response)?)
+}
+
+pub fn url_encode(info_hash: &[u8; 20]) -> String {
+    let mut url_encoded = String::new();
+    for byte in info_hash {
+        url_encoded.push_str(&format!("%{:02x}", byte));
+    }
+    url_encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_separator() {
+        assert_eq!(with_query("http://t/announce", "port=1"), "http://t/announce?port=1");
+        assert_eq!(with_query("http://t/announce?passkey=x", "port=1"), "http://t/announce?passkey=x&port=1");
+    }
 }
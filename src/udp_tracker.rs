@@ -0,0 +1,359 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::net::UdpSocket;
+use crate::error::TrackerError;
+use crate::random::random_u32;
+
+/// Magic constant sent as connection id of connect requests
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// A connection id may be used for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Requests are retransmitted after 15 * 2 ^ n seconds (BEP 15)
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRIES: u32 = 8;
+
+/// Announce events, numbered as in BEP 15
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UdpEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpAnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: UdpEvent,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpAnnounceResponse {
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Swarm statistics of one torrent returned by a scrape
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpScrape {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// Client for a `udp://` tracker (BEP 15)
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// Last connection id received and when it was received
+    connection: Option<(u64, Instant)>,
+    /// Delay before the first retransmission, doubled after each one
+    pub timeout: Duration,
+    pub max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolve the tracker of a `udp://host:port/...` URL
+    pub async fn connect(url: &str) -> Result<UdpTracker> {
+        let parsed = reqwest::Url::parse(url).with_context(|| format!("Invalid tracker URL {}", url))?;
+        if parsed.scheme() != "udp" {
+            bail!("Not a UDP tracker URL: {}", url);
+        }
+        let host = parsed.host_str().context("Tracker URL has no host")?;
+        let port = parsed.port().context("Tracker URL has no port")?;
+        let addr = tokio::net::lookup_host((host, port)).await?
+            .next()
+            .with_context(|| format!("Could not resolve tracker {}", host))?;
+
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(UdpTracker {
+            socket,
+            addr,
+            connection: None,
+            timeout: BASE_TIMEOUT,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    pub async fn announce(&mut self, request: &UdpAnnounceRequest) -> Result<UdpAnnounceResponse> {
+        let mut body = BytesMut::with_capacity(82);
+        body.put_slice(&request.info_hash);
+        body.put_slice(&request.peer_id);
+        body.put_u64(request.downloaded);
+        body.put_u64(request.left);
+        body.put_u64(request.uploaded);
+        body.put_u32(request.event as u32);
+        body.put_u32(0); // IP address: use the sender address
+        body.put_u32(random_u32()); // key
+        body.put_i32(-1); // num_want: default
+        body.put_u16(request.port);
+
+        let mut response = &self.request(ACTION_ANNOUNCE, &body).await?[..];
+        if response.len() < 12 {
+            bail!("Announce response too short: {} bytes", response.len());
+        }
+        let interval = response.get_u32();
+        let leechers = response.get_u32();
+        let seeders = response.get_u32();
+
+        // Peers have the address family of the tracker we talk to
+        let peers = if self.addr.is_ipv4() {
+            response.chunks_exact(6)
+                .map(|chunk| SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
+                    u16::from_be_bytes([chunk[4], chunk[5]]),
+                )))
+                .collect()
+        } else {
+            response.chunks_exact(18)
+                .map(|chunk| {
+                    let ip: [u8; 16] = chunk[..16].try_into().expect("chunk is 18 bytes long");
+                    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), u16::from_be_bytes([chunk[16], chunk[17]]), 0, 0))
+                })
+                .collect()
+        };
+
+        Ok(UdpAnnounceResponse { interval, leechers, seeders, peers })
+    }
+
+    /// Query the statistics of up to about 74 torrents at once
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<UdpScrape>> {
+        let body = info_hashes.concat();
+        let mut response = &self.request(ACTION_SCRAPE, &body).await?[..];
+        if response.len() < 12 * info_hashes.len() {
+            bail!("Scrape response too short: {} bytes for {} torrents", response.len(), info_hashes.len());
+        }
+        Ok(info_hashes.iter()
+            .map(|_| UdpScrape {
+                seeders: response.get_u32(),
+                completed: response.get_u32(),
+                leechers: response.get_u32(),
+            })
+            .collect())
+    }
+
+    /// Connection id to send with requests, if the cached one has not expired
+    fn connection_id(&self) -> Option<u64> {
+        self.connection
+            .filter(|(_, received)| received.elapsed() < CONNECTION_ID_LIFETIME)
+            .map(|(id, _)| id)
+    }
+
+    /// Send a request, retransmitting it until the tracker answers, and return the
+    /// response without its action and transaction id
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        for n in 0..=self.max_retries {
+            let timeout = self.timeout * 2u32.pow(n);
+
+            // An expired connection id must be requested again before retransmitting
+            let connection_id = match self.connection_id() {
+                Some(id) => id,
+                None => {
+                    let Some(response) = self.exchange(PROTOCOL_ID, ACTION_CONNECT, &[], timeout).await? else {
+                        continue;
+                    };
+                    if response.len() < 8 {
+                        bail!("Connect response too short: {} bytes", response.len());
+                    }
+                    let id = (&response[..]).get_u64();
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            if let Some(response) = self.exchange(connection_id, action, body, timeout).await? {
+                return Ok(response);
+            }
+        }
+        bail!("Tracker {} did not answer after {} retransmissions", self.addr, self.max_retries)
+    }
+
+    /// Send one packet and wait for the matching response, `None` on timeout
+    async fn exchange(&self, connection_id: u64, action: u32, body: &[u8], timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let transaction_id = random_u32();
+        let mut packet = BytesMut::with_capacity(16 + body.len());
+        packet.put_u64(connection_id);
+        packet.put_u32(action);
+        packet.put_u32(transaction_id);
+        packet.put_slice(body);
+        self.socket.send(&packet).await.context("Error sending to tracker")?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let Ok(received) = tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await else {
+                return Ok(None);
+            };
+            let length = received.context("Error receiving from tracker")?;
+            let mut response = &buf[..length];
+            if response.len() < 8 {
+                continue;
+            }
+            let response_action = response.get_u32();
+            // Late answers to previous transmissions are dropped
+            if response.get_u32() != transaction_id {
+                continue;
+            }
+            if response_action == ACTION_ERROR {
+                return Err(TrackerError::Failure(String::from_utf8_lossy(response).into_owned()).into());
+            }
+            if response_action != action {
+                bail!("Tracker answered action {} to action {}", response_action, action);
+            }
+            return Ok(Some(response.to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    /// Connection id and action of every packet a stand-in tracker received
+    type Log = Arc<Mutex<Vec<(u64, u32)>>>;
+
+    /// Local stand-in for a UDP tracker, leaving the first `dropped` packets unanswered
+    async fn stand_in(dropped: usize) -> (String, Log) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let log = Log::default();
+        let received = log.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (length, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut packet = &buf[..length];
+                let connection_id = packet.get_u64();
+                let action = packet.get_u32();
+                let transaction_id = packet.get_u32();
+                let count = {
+                    let mut log = received.lock().unwrap();
+                    log.push((connection_id, action));
+                    log.len()
+                };
+                if count <= dropped {
+                    continue;
+                }
+
+                let mut response = BytesMut::new();
+                if action != ACTION_CONNECT && connection_id != CONNECTION_ID {
+                    response.put_u32(ACTION_ERROR);
+                    response.put_u32(transaction_id);
+                    response.put_slice(b"Invalid connection id");
+                } else {
+                    response.put_u32(action);
+                    response.put_u32(transaction_id);
+                    match action {
+                        ACTION_CONNECT => response.put_u64(CONNECTION_ID),
+                        ACTION_ANNOUNCE => {
+                            response.put_u32(1800);
+                            response.put_u32(2);
+                            response.put_u32(1);
+                            response.put_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                        }
+                        ACTION_SCRAPE => {
+                            for _ in 0..packet.len() / 20 {
+                                response.put_u32(5);
+                                response.put_u32(10);
+                                response.put_u32(3);
+                            }
+                        }
+                        _ => panic!("Unexpected action {}", action),
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        (url, log)
+    }
+
+    fn announce_request() -> UdpAnnounceRequest {
+        UdpAnnounceRequest {
+            info_hash: [0xaa; 20],
+            peer_id: [0xbb; 20],
+            downloaded: 0,
+            left: 100,
+            uploaded: 0,
+            event: UdpEvent::Started,
+            port: 6881,
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_announce_scrape() {
+        let (url, log) = stand_in(0).await;
+        let mut tracker = UdpTracker::connect(&url).await.unwrap();
+
+        let response = tracker.announce(&announce_request()).await.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.leechers, 2);
+        assert_eq!(response.seeders, 1);
+        assert_eq!(response.peers, vec!["10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6882".parse::<SocketAddr>().unwrap()]);
+
+        let scrapes = tracker.scrape(&[[0xaa; 20], [0xcc; 20]]).await.unwrap();
+        assert_eq!(scrapes, vec![UdpScrape { seeders: 5, completed: 10, leechers: 3 }; 2]);
+
+        // The connection id is reused while it is valid
+        assert_eq!(*log.lock().unwrap(), vec![
+            (PROTOCOL_ID, ACTION_CONNECT),
+            (CONNECTION_ID, ACTION_ANNOUNCE),
+            (CONNECTION_ID, ACTION_SCRAPE),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn expired_connection_id() {
+        let (url, log) = stand_in(0).await;
+        let mut tracker = UdpTracker::connect(&url).await.unwrap();
+        let expired = Instant::now().checked_sub(CONNECTION_ID_LIFETIME).unwrap();
+        tracker.connection = Some((0xdead, expired));
+
+        tracker.announce(&announce_request()).await.unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![(PROTOCOL_ID, ACTION_CONNECT), (CONNECTION_ID, ACTION_ANNOUNCE)]);
+    }
+
+    #[tokio::test]
+    async fn retransmits_unanswered_requests() {
+        // The first connect is lost
+        let (url, log) = stand_in(1).await;
+        let mut tracker = UdpTracker::connect(&url).await.unwrap();
+        tracker.timeout = Duration::from_millis(50);
+
+        tracker.announce(&announce_request()).await.unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![
+            (PROTOCOL_ID, ACTION_CONNECT),
+            (PROTOCOL_ID, ACTION_CONNECT),
+            (CONNECTION_ID, ACTION_ANNOUNCE),
+        ]);
+
+        // A tracker that never answers is given up on
+        let (url, _) = stand_in(usize::MAX).await;
+        let mut tracker = UdpTracker::connect(&url).await.unwrap();
+        tracker.timeout = Duration::from_millis(10);
+        tracker.max_retries = 2;
+        assert!(tracker.announce(&announce_request()).await.is_err());
+    }
+}
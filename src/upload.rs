@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use anyhow::{bail, Result};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::sync::broadcast::error::RecvError;
+use crate::announcer::TransferStats;
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, ChokerHandle};
+use crate::download::verify_piece;
+use crate::extension::ExtensionRegistry;
+use crate::fast::{allowed_fast_set, ALLOWED_FAST_COUNT};
+use crate::listener::InboundPeer;
+use crate::peers::PeerMessage;
+use crate::session::PeerSession;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+
+/// Largest block a peer may request, larger requests are a protocol violation
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+/// Maximum number of peers served at the same time while seeding
+const MAX_SEED_PEERS: usize = 32;
+
+/// Verified pieces we have on disk, shared by every session of a torrent
+pub struct PieceStore {
+    torrent: Arc<Torrent>,
+    storage: Storage,
+    stats: Arc<TransferStats>,
+    have: Mutex<Have>,
+    /// Announces pieces stored while sessions are running
+    completed: broadcast::Sender<u32>,
+    choker: Arc<Choker>,
+}
+
+/// Pieces of a store, counted as they are added so the count never needs a scan
+struct Have {
+    bitfield: Bitfield,
+    count: u32,
+}
+
+impl PieceStore {
+    /// A store holding the pieces of `have`, which must already be verified
+    pub fn new(torrent: Arc<Torrent>, storage: Storage, stats: Arc<TransferStats>, have: Bitfield) -> PieceStore {
+        let (completed, _) = broadcast::channel(torrent.piece_count().max(1) as usize);
+        let count = (0..torrent.piece_count()).filter(|&index| have.has(index)).count() as u32;
+        let choker = Choker::start(count == torrent.piece_count());
+        let have = Mutex::new(Have { bitfield: have, count });
+        PieceStore { torrent, storage, stats, have, completed, choker }
+    }
+
+    /// Hash every piece found in storage, returning the bitfield of the valid ones
+    pub fn check(torrent: &Torrent, storage: &Storage) -> Result<Bitfield> {
+        let mut have = Bitfield::with_pieces(torrent.piece_count() as usize);
+        for index in 0..torrent.piece_count() {
+            let data = storage.read_block(index, 0, torrent.piece_size(index))?;
+            if verify_piece(torrent, index, &data) {
+                have.set(index);
+            }
+        }
+        Ok(have)
+    }
+
+    pub fn torrent(&self) -> &Arc<Torrent> {
+        &self.torrent
+    }
+
+    pub fn stats(&self) -> &Arc<TransferStats> {
+        &self.stats
+    }
+
+    pub fn bitfield(&self) -> Bitfield {
+        self.have.lock().unwrap().bitfield.clone()
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        self.have.lock().unwrap().bitfield.has(index)
+    }
+
+    /// Number of pieces we have
+    pub fn count(&self) -> u32 {
+        self.have.lock().unwrap().count
+    }
+
+    /// Write a verified piece, count it as downloaded and let every session announce it
+    pub fn store_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        self.storage.write_piece(index, data)?;
+        self.stats.add_downloaded(data.len() as u64);
+        let mut have = self.have.lock().unwrap();
+        // A piece downloaded twice is only counted once
+        if !have.bitfield.has(index) {
+            have.bitfield.set(index);
+            have.count += 1;
+        }
+        let seeding = have.count == self.torrent.piece_count();
+        drop(have);
+        if seeding {
+            self.choker.set_seeding();
+        }
+        // Nobody listening is fine, sessions only subscribe while connected
+        let _ = self.completed.send(index);
+        Ok(())
+    }
+
+    /// Read a requested block, `None` if we do not have its piece
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Option<Vec<u8>>> {
+        if index >= self.torrent.piece_count() {
+            bail!("Request for piece {} out of range", index);
+        }
+        if length == 0 || length > MAX_REQUEST_LENGTH || begin as u64 + length as u64 > self.torrent.piece_size(index) as u64 {
+            bail!("Invalid request for piece {}: begin {}, length {}", index, begin, length);
+        }
+        if !self.has(index) {
+            return Ok(None);
+        }
+        self.storage.read_block(index, begin, length).map(Some)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.completed.subscribe()
+    }
+}
+
+/// Upload side of the connection to one peer
+pub struct Upload {
+    store: Arc<PieceStore>,
+    completed: broadcast::Receiver<u32>,
+    choker: ChokerHandle,
+    /// Whether the peer supports the Fast Extension, so requests we do not serve are rejected
+    fast: bool,
+    /// Pieces the peer may download while choked
+    allowed_fast: HashSet<u32>,
+}
+
+impl Upload {
+    pub fn new(store: Arc<PieceStore>) -> Upload {
+        let completed = store.subscribe();
+        let choker = store.choker.register();
+        Upload { store, completed, choker, fast: false, allowed_fast: HashSet::new() }
+    }
+
+    /// Use the Fast Extension with the peer at `ip`, returning the `AllowedFast` messages to send it
+    pub fn enable_fast(&mut self, ip: IpAddr, info_hash: &[u8; 20]) -> Vec<PeerMessage> {
+        self.fast = true;
+        // BEP 6 only defines the allowed fast set of IPv4 peers
+        if let IpAddr::V4(ip) = ip {
+            let piece_count = self.store.torrent().piece_count();
+            self.allowed_fast = allowed_fast_set(ip, info_hash, piece_count, ALLOWED_FAST_COUNT).into_iter().collect();
+        }
+        self.allowed_fast.iter().map(|&index| PeerMessage::AllowedFast(index)).collect()
+    }
+
+    /// Message telling the peer which pieces we have, sent right after the handshake.
+    /// `None` when we have no piece yet and the peer does not support the Fast Extension.
+    pub fn bitfield_message(&self) -> Option<PeerMessage> {
+        let count = self.store.count();
+        if self.fast && count == self.store.torrent().piece_count() {
+            return Some(PeerMessage::HaveAll);
+        }
+        if count == 0 {
+            return self.fast.then_some(PeerMessage::HaveNone);
+        }
+        Some(PeerMessage::Bitfield(self.store.bitfield()))
+    }
+
+    /// Wait for the next message to send on our own: `Have` for a piece we stored,
+    /// or `Choke`/`Unchoke` when the choker changes its mind about the peer
+    pub async fn next_message(&mut self, am_choking: bool) -> PeerMessage {
+        loop {
+            tokio::select! {
+                completed = self.completed.recv() => match completed {
+                    Ok(index) => return PeerMessage::Have(index),
+                    // Missed announces only cost the peer a chance to request the piece from us
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => std::future::pending::<()>().await,
+                },
+                choked = self.choker.changed() => {
+                    if choked != am_choking {
+                        return if choked { PeerMessage::Choke } else { PeerMessage::Unchoke };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a message of the peer about uploads, returning the messages to answer with.
+    /// Returns `None` for messages that are not about uploads.
+    pub fn handle(&mut self, received: &PeerMessage, am_choking: bool) -> Result<Option<Vec<PeerMessage>>> {
+        let replies = match *received {
+            // The choker decides whether the peer gets served
+            PeerMessage::Interested => {
+                self.choker.set_interested(true);
+                Vec::new()
+            }
+            PeerMessage::NotInterested => {
+                self.choker.set_interested(false);
+                Vec::new()
+            }
+            PeerMessage::Request { index, begin, length } => {
+                // Requests sent before we choked the peer are dropped, unless the piece is allowed fast
+                if am_choking && !self.allowed_fast.contains(&index) {
+                    return Ok(Some(self.reject(index, begin, length)));
+                }
+                match self.store.read_block(index, begin, length)? {
+                    Some(block) => {
+                        self.store.stats().add_uploaded(block.len() as u64);
+                        self.choker.stats().add_uploaded(block.len() as u64);
+                        vec![PeerMessage::Piece { index, begin, block: block.into() }]
+                    }
+                    None => self.reject(index, begin, length),
+                }
+            }
+            // Blocks are sent as soon as they are requested, there is nothing to cancel
+            PeerMessage::Cancel { .. } => Vec::new(),
+            // We pick the pieces to download ourselves
+            PeerMessage::SuggestPiece(_) => Vec::new(),
+            // Blocks we receive rate the peer for the choker, the download handles them
+            PeerMessage::Piece { ref block, .. } => {
+                self.choker.stats().add_downloaded(block.len() as u64);
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(replies))
+    }
+
+    /// Answer to a request we do not serve: peers supporting the Fast Extension expect
+    /// a reject, the others notice from the choke or simply never get the block
+    fn reject(&self, index: u32, begin: u32, length: u32) -> Vec<PeerMessage> {
+        if self.fast {
+            vec![PeerMessage::RejectRequest { index, begin, length }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Serve our pieces to `peers` and to the peers connecting to us, until `inbound` is closed
+pub async fn seed(store: Arc<PieceStore>, peers: Vec<SocketAddr>, mut inbound: mpsc::Receiver<InboundPeer>, info_hash: [u8; 20], peer_id: [u8; 20]) {
+    let slots = Arc::new(Semaphore::new(MAX_SEED_PEERS));
+    let extensions = Arc::new(ExtensionRegistry::new());
+    for addr in peers {
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            break;
+        };
+        let upload = Upload::new(store.clone());
+        let piece_count = store.torrent().piece_count();
+        let extensions = extensions.clone();
+        tokio::spawn(async move {
+            let connection = PeerSession::connect(addr, &info_hash, &peer_id, piece_count, extensions, Some(upload)).await;
+            serve_peer(addr, connection).await;
+            drop(slot);
+        });
+    }
+    while let Some(peer) = inbound.recv().await {
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            println!("Too many inbound peers, dropping {}", peer.addr);
+            continue;
+        };
+        let upload = Upload::new(store.clone());
+        let piece_count = store.torrent().piece_count();
+        let extensions = extensions.clone();
+        tokio::spawn(async move {
+            let addr = peer.addr;
+            let connection = PeerSession::accept(peer, piece_count, extensions, Some(upload)).await;
+            serve_peer(addr, connection).await;
+            drop(slot);
+        });
+    }
+}
+
+async fn serve_peer(addr: SocketAddr, connection: Result<PeerSession>) {
+    let result = match connection {
+        Ok(peer) => peer.serve().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        println!("Stopped serving peer {}: {:#}", addr, err);
+    }
+}